@@ -0,0 +1,502 @@
+// focus -- focus interaction tool
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use anyhow::{bail, Result};
+use kaleidoscope_focus::devices::{Device, DeviceInfo, Registry};
+use kaleidoscope_focus::{key::Key, led::Rgb};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The version of the backup format written by this tool.
+///
+/// Version 1 files - without a `format` field and metadata - can still be read.
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupData {
+    #[serde(default = "BackupData::v1")]
+    pub format: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    pub restore: Vec<String>,
+    pub commands: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Metadata {
+    /// The keyboard the backup was made from, if it could be identified.
+    pub device: Option<DeviceIdentity>,
+    /// The reply to `version`, if the firmware supports it.
+    pub firmware: Option<String>,
+    /// When the backup was made, in seconds since the Unix epoch.
+    pub created: u64,
+    /// The name and version of the tool that made the backup.
+    pub tool: String,
+    /// A CRC-32 checksum of the backed up commands, in hexadecimal.
+    pub checksum: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub product: Option<String>,
+}
+
+impl DeviceIdentity {
+    /// Look the device up in the registry.
+    pub fn device<'a>(&self, registry: &'a Registry) -> Option<&'a Device> {
+        registry.lookup(self.vid, self.pid)
+    }
+}
+
+impl From<&DeviceInfo> for DeviceIdentity {
+    fn from(device: &DeviceInfo) -> Self {
+        Self {
+            vid: device.vid,
+            pid: device.pid,
+            product: device.product.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vid, self.pid)?;
+        if let Some(product) = &self.product {
+            write!(f, " ({})", product)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for BackupData {
+    fn default() -> Self {
+        Self {
+            format: FORMAT_VERSION,
+            metadata: None,
+            restore: vec![],
+            commands: HashMap::new(),
+        }
+    }
+}
+
+impl BackupData {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn v1() -> u32 {
+        1
+    }
+
+    /// Record where, and when the backup was made, along with its checksum.
+    pub fn seal(&mut self, device: Option<DeviceIdentity>, firmware: Option<String>) {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        self.metadata = Some(Metadata {
+            device,
+            firmware,
+            created,
+            tool: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            checksum: self.checksum(),
+        });
+    }
+
+    /// Check that the backup can be restored by this tool, and that it was
+    /// not modified since it was made.
+    pub fn verify(&self) -> Result<()> {
+        if self.format > FORMAT_VERSION {
+            bail!(
+                "Unsupported backup format version {} (the newest supported is {})",
+                self.format,
+                FORMAT_VERSION
+            );
+        }
+        if let Some(metadata) = &self.metadata {
+            let checksum = self.checksum();
+            if metadata.checksum != checksum {
+                bail!(
+                    "Checksum mismatch: the backup says {}, its contents say {}",
+                    metadata.checksum,
+                    checksum
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe the ways in which the target keyboard differs from the one the
+    /// backup was made from.
+    pub fn mismatches(
+        &self,
+        device: Option<&DeviceIdentity>,
+        firmware: Option<&str>,
+    ) -> Vec<String> {
+        let mut mismatches = vec![];
+        let metadata = match &self.metadata {
+            Some(metadata) => metadata,
+            None => return mismatches,
+        };
+
+        if let (Some(ours), Some(theirs)) = (&metadata.device, device) {
+            if (ours.vid, ours.pid) != (theirs.vid, theirs.pid) {
+                mismatches.push(format!(
+                    "The backup was made from {}, but restoring to {}",
+                    ours, theirs
+                ));
+            }
+        }
+        if let (Some(ours), Some(theirs)) = (&metadata.firmware, firmware) {
+            if ours != theirs {
+                mismatches.push(format!(
+                    "The backup was made with firmware `{}`, but the keyboard runs `{}`",
+                    ours, theirs
+                ));
+            }
+        }
+        mismatches
+    }
+
+    /// Checksum the commands, in the order they are restored in.
+    fn checksum(&self) -> String {
+        let mut crc = Crc32::new();
+        for cmd in &self.restore {
+            if let Some(value) = self.commands.get(cmd) {
+                crc.update(cmd.as_bytes());
+                crc.update(b"\n");
+                crc.update(value.as_bytes());
+                crc.update(b"\n");
+            }
+        }
+        format!("{:08x}", crc.finish())
+    }
+}
+
+/// Return whether two values of a setting are the same, ignoring differences
+/// in whitespace.
+pub fn equivalent(a: &str, b: &str) -> bool {
+    a.split_whitespace().eq(b.split_whitespace())
+}
+
+/// Describe the differences between the current value of a setting, and the
+/// one in the backup, one change per line. Returns nothing if they're equal.
+///
+/// Keymaps, colormaps and palettes are compared key by key, LED by LED, and
+/// color by color, respectively. If the device is known, keys are located by
+/// layer, row and column, and LEDs by layer and index.
+pub fn diff(command: &str, current: &str, backup: &str, device: Option<&Device>) -> Vec<String> {
+    let ours: Vec<&str> = current.split_whitespace().collect();
+    let theirs: Vec<&str> = backup.split_whitespace().collect();
+    if ours == theirs {
+        return vec![];
+    }
+
+    let key_name = |v: &&str| {
+        v.parse::<u16>()
+            .map_or(v.to_string(), |k| Key::from(k).to_string())
+    };
+    let color = |c: &[&str]| match c
+        .iter()
+        .map(|v| v.parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(rgb) if rgb.len() == 3 => Rgb::new(rgb[0], rgb[1], rgb[2]).to_string(),
+        _ => c.join(" "),
+    };
+    let key_position = |i: usize| match device.and_then(|d| d.geometry) {
+        Some(g) if g.keys() > 0 => format!(
+            "layer {}, row {}, col {}",
+            i / g.keys(),
+            i % g.keys() / g.cols,
+            i % g.cols
+        ),
+        _ => format!("key {}", i),
+    };
+    let led_position = |i: usize| match device.and_then(|d| d.leds) {
+        Some(leds) if leds > 0 => format!("layer {}, LED {}", i / leds, i % leds),
+        _ => format!("LED {}", i),
+    };
+
+    match command {
+        "keymap.custom" => diff_lists(
+            &ours.iter().map(key_name).collect::<Vec<_>>(),
+            &theirs.iter().map(key_name).collect::<Vec<_>>(),
+            key_position,
+        ),
+        "colormap.map" => diff_lists(&ours, &theirs, led_position),
+        "palette" => diff_lists(
+            &ours.chunks(3).map(color).collect::<Vec<_>>(),
+            &theirs.chunks(3).map(color).collect::<Vec<_>>(),
+            |i| format!("color {}", i),
+        ),
+        _ => vec![format!("{} -> {}", show(current), show(backup))],
+    }
+}
+
+fn diff_lists<T: AsRef<str>>(
+    ours: &[T],
+    theirs: &[T],
+    position: impl Fn(usize) -> String,
+) -> Vec<String> {
+    (0..ours.len().max(theirs.len()))
+        .filter_map(|i| {
+            let (a, b) = (ours.get(i).map(T::as_ref), theirs.get(i).map(T::as_ref));
+            (a != b).then(|| {
+                format!(
+                    "{}: {} -> {}",
+                    position(i),
+                    a.unwrap_or("(none)"),
+                    b.unwrap_or("(none)")
+                )
+            })
+        })
+        .collect()
+}
+
+fn show(value: &str) -> &str {
+    if value.is_empty() {
+        "(empty)"
+    } else {
+        value
+    }
+}
+
+/// A minimal, bitwise implementation of CRC-32 (IEEE).
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(!0)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kaleidoscope_focus::keymap::Geometry;
+
+    fn sealed() -> BackupData {
+        let mut backup = BackupData::new();
+        backup.restore = vec!["led.brightness".to_string(), "palette".to_string()];
+        backup
+            .commands
+            .insert("led.brightness".into(), "160".into());
+        backup.commands.insert("palette".into(), "0 0 0".into());
+        backup.seal(
+            Some(DeviceIdentity {
+                vid: 0x3496,
+                pid: 0x0006,
+                product: Some("Model 100".to_string()),
+            }),
+            Some("1.0".to_string()),
+        );
+        backup
+    }
+
+    #[test]
+    fn v1_backups_are_read_and_verified() {
+        let backup: BackupData = serde_json::from_str(
+            r#"{"restore": ["led.brightness"], "commands": {"led.brightness": "160"}}"#,
+        )
+        .unwrap();
+        assert_eq!(backup.format, 1);
+        assert!(backup.metadata.is_none());
+        assert!(backup.verify().is_ok());
+        assert!(backup.mismatches(None, Some("1.0")).is_empty());
+    }
+
+    #[test]
+    fn sealed_backups_survive_a_round_trip() {
+        let json = serde_json::to_string(&sealed()).unwrap();
+        let backup: BackupData = serde_json::from_str(&json).unwrap();
+        assert_eq!(backup.format, FORMAT_VERSION);
+        assert!(backup.verify().is_ok());
+    }
+
+    #[test]
+    fn tampered_backups_fail_the_checksum() {
+        let mut backup = sealed();
+        backup
+            .commands
+            .insert("led.brightness".into(), "255".into());
+        assert!(backup.verify().is_err());
+
+        // So does reordering the commands.
+        let mut backup = sealed();
+        backup.restore.reverse();
+        assert!(backup.verify().is_err());
+    }
+
+    #[test]
+    fn crc32_matches_the_reference() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn newer_formats_are_rejected() {
+        let mut backup = sealed();
+        backup.format = FORMAT_VERSION + 1;
+        assert!(backup.verify().is_err());
+    }
+
+    fn keyboard() -> Device {
+        Device::new("Keyboard", 0x1234, 0x5678)
+            .with_geometry(Geometry { rows: 4, cols: 16 })
+            .with_leds(64)
+    }
+
+    #[test]
+    fn equal_settings_have_no_diff() {
+        assert!(diff("keymap.custom", "1  2\n3", "1 2 3", Some(&keyboard())).is_empty());
+        assert!(diff("led.brightness", "160", "160", None).is_empty());
+        assert!(equivalent("1  2\n3", "1 2 3"));
+        assert!(!equivalent("1 2", "1 2 3"));
+    }
+
+    #[test]
+    fn keys_are_located_by_layer_row_and_column() {
+        let ours = vec!["0"; 128];
+        let mut theirs = ours.clone();
+        // Layer 1, row 2, column 5.
+        theirs[64 + 2 * 16 + 5] = "4";
+        assert_eq!(
+            diff(
+                "keymap.custom",
+                &ours.join(" "),
+                &theirs.join(" "),
+                Some(&keyboard())
+            ),
+            vec!["layer 1, row 2, col 5: Key_NoKey -> Key_A"]
+        );
+        assert_eq!(
+            diff("keymap.custom", &ours.join(" "), &theirs.join(" "), None),
+            vec!["key 101: Key_NoKey -> Key_A"]
+        );
+    }
+
+    #[test]
+    fn leds_are_located_by_layer_and_index() {
+        let ours = vec!["0"; 128];
+        let mut theirs = ours.clone();
+        theirs[64 + 10] = "3";
+        assert_eq!(
+            diff(
+                "colormap.map",
+                &ours.join(" "),
+                &theirs.join(" "),
+                Some(&keyboard())
+            ),
+            vec!["layer 1, LED 10: 0 -> 3"]
+        );
+        let unlit = keyboard().with_leds(0);
+        assert_eq!(
+            diff(
+                "colormap.map",
+                &ours.join(" "),
+                &theirs.join(" "),
+                Some(&unlit)
+            ),
+            vec!["LED 74: 0 -> 3"]
+        );
+    }
+
+    #[test]
+    fn palettes_are_compared_color_by_color() {
+        assert_eq!(
+            diff("palette", "0 0 0 255 0 0", "0 0 0 0 255 16", None),
+            vec!["color 1: #ff0000 -> #00ff10"]
+        );
+        // Incomplete colors are shown as they are.
+        assert_eq!(
+            diff("palette", "0 0 0 1 2", "0 0 0 1 3", None),
+            vec!["color 1: 1 2 -> 1 3"]
+        );
+    }
+
+    #[test]
+    fn missing_entries_are_shown_as_none() {
+        assert_eq!(
+            diff("colormap.map", "1 2 3", "1 2", None),
+            vec!["LED 2: 3 -> (none)"]
+        );
+        assert_eq!(
+            diff("palette", "0 0 0", "0 0 0 255 255 255", None),
+            vec!["color 1: (none) -> #ffffff"]
+        );
+    }
+
+    #[test]
+    fn other_settings_are_compared_as_a_whole() {
+        assert_eq!(
+            diff("led.brightness", "100", "160", None),
+            vec!["100 -> 160"]
+        );
+        assert_eq!(
+            diff("settings.defaultLayer", "", "1", None),
+            vec!["(empty) -> 1"]
+        );
+    }
+
+    #[test]
+    fn mismatches_are_reported() {
+        let backup = sealed();
+        let model01 = DeviceIdentity {
+            vid: 0x1209,
+            pid: 0x2301,
+            product: None,
+        };
+        let same = DeviceIdentity {
+            vid: 0x3496,
+            pid: 0x0006,
+            product: None,
+        };
+
+        assert!(backup.mismatches(Some(&same), Some("1.0")).is_empty());
+        assert_eq!(
+            backup.mismatches(Some(&model01), Some("2.0")),
+            vec![
+                "The backup was made from 3496:0006 (Model 100), but restoring to 1209:2301",
+                "The backup was made with firmware `1.0`, but the keyboard runs `2.0`",
+            ]
+        );
+        // Nothing is reported about what is not known.
+        assert!(backup.mismatches(None, None).is_empty());
+        let mut unknown = sealed();
+        let metadata = unknown.metadata.as_mut().unwrap();
+        metadata.device = None;
+        metadata.firmware = None;
+        assert!(unknown.mismatches(Some(&model01), Some("2.0")).is_empty());
+    }
+}
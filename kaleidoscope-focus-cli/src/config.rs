@@ -0,0 +1,86 @@
+// focus -- focus interaction tool
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use anyhow::{bail, Context, Result};
+use kaleidoscope_focus::devices::{Device, Registry};
+use kaleidoscope_focus::keymap::Geometry;
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::path::PathBuf;
+
+/// A device, as described in the user's device file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceEntry {
+    name: String,
+    #[serde(deserialize_with = "hex")]
+    vid: u16,
+    #[serde(deserialize_with = "hex")]
+    pid: u16,
+    rows: Option<usize>,
+    cols: Option<usize>,
+    leds: Option<usize>,
+}
+
+/// Build the registry of supported devices: the ones known to the library,
+/// extended with - or overridden by - those in the user's device file, if
+/// there is one.
+pub fn registry() -> Result<Registry> {
+    let mut registry = Registry::default();
+    let path = match devices_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(registry),
+    };
+
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let entries: Vec<DeviceEntry> = serde_json::from_str(&contents)
+        .with_context(|| format!("Unable to parse {}", path.display()))?;
+    for entry in entries {
+        let mut device = Device::new(&entry.name, entry.vid, entry.pid);
+        match (entry.rows, entry.cols) {
+            (Some(rows), Some(cols)) => device = device.with_geometry(Geometry { rows, cols }),
+            (None, None) => {}
+            _ => bail!(
+                "{}: `{}` must have both `rows` and `cols`, or neither",
+                path.display(),
+                entry.name
+            ),
+        }
+        if let Some(leds) = entry.leds {
+            device = device.with_leds(leds);
+        }
+        registry.add(device);
+    }
+    Ok(registry)
+}
+
+/// Where the device file is: `$XDG_CONFIG_HOME/focus/devices.json`, falling
+/// back to `~/.config/focus/devices.json`, or `%APPDATA%\focus\devices.json` on
+/// Windows.
+fn devices_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("focus").join("devices.json"))
+}
+
+/// Deserialize a USB ID, written in hexadecimal, with or without a `0x` prefix.
+fn hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    u16::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|_| serde::de::Error::custom(format!("invalid USB ID `{}`", s)))
+}
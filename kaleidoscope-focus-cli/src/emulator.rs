@@ -0,0 +1,267 @@
+// focus -- focus interaction tool
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use kaleidoscope_focus::keymap::Geometry;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Model {
+    Model100,
+    Atreus,
+    Model01,
+}
+
+impl Model {
+    fn geometry(self) -> Geometry {
+        match self {
+            Model::Model100 => Geometry::MODEL100,
+            Model::Atreus => Geometry::ATREUS,
+            Model::Model01 => Geometry::MODEL01,
+        }
+    }
+
+    fn has_leds(self) -> bool {
+        !matches!(self, Model::Atreus)
+    }
+}
+
+/// The number of layers in the custom keymap, and the colormap.
+const LAYERS: usize = 10;
+const MACROS_SIZE: usize = 512;
+const TAPDANCE_SIZE: usize = 64;
+const EEPROM_SIZE: usize = 1024;
+
+/// A setting of the emulated keyboard.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Setting {
+    /// A value replaced as a whole by writes.
+    Value(String),
+    /// A fixed size storage area, written from the start: values beyond the
+    /// ones written are left untouched, and values beyond its end are ignored.
+    Storage(Vec<u32>),
+}
+
+/// An emulated keyboard, speaking Focus.
+#[derive(Serialize, Deserialize)]
+pub struct Keyboard {
+    settings: BTreeMap<String, Setting>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Keyboard {
+    /// Create a keyboard, with the settings of a freshly flashed `model`.
+    pub fn new(model: Model) -> Self {
+        let keys = model.geometry().keys();
+        let mut settings = BTreeMap::new();
+        let mut value = |k: &str, v: &str| settings.insert(k.to_string(), Setting::Value(v.into()));
+
+        value(
+            "version",
+            concat!("focus-emulator ", env!("CARGO_PKG_VERSION")),
+        );
+        value("keymap.onlyCustom", "0");
+        value("settings.defaultLayer", "0");
+        value("settings.valid?", "1");
+        value("settings.version", "1");
+        value("eeprom.free", &(EEPROM_SIZE / 2).to_string());
+        if model.has_leds() {
+            value("led.brightness", "160");
+            value("led_mode.default", "0");
+        }
+
+        let mut storage =
+            |k: &str, v: Vec<u32>| settings.insert(k.to_string(), Setting::Storage(v));
+        storage("keymap.custom", vec![0; keys * LAYERS]);
+        storage("keymap.default", vec![0; keys * LAYERS]);
+        storage("eeprom.contents", vec![255; EEPROM_SIZE]);
+        storage("macros.map", prefixed(&[0, 0], 255, MACROS_SIZE));
+        storage("tapdance.map", prefixed(&[0, 0], 65535, TAPDANCE_SIZE));
+        if model.has_leds() {
+            storage("palette", vec![0; 16 * 3]);
+            storage("colormap.map", vec![0; keys * LAYERS]);
+        }
+
+        Self {
+            settings,
+            path: None,
+        }
+    }
+
+    /// Load the keyboard from a state file, or if it does not exist yet,
+    /// create a new one. The state is saved to the file after every change.
+    pub fn load_or_new(model: Model, path: PathBuf) -> Result<Self> {
+        let mut keyboard = if path.exists() {
+            let state = fs::read_to_string(&path)
+                .with_context(|| format!("Unable to read {}", path.display()))?;
+            serde_json::from_str(&state)
+                .with_context(|| format!("Unable to parse {}", path.display()))?
+        } else {
+            Self::new(model)
+        };
+        keyboard.path = Some(path);
+        keyboard.save()?;
+        Ok(keyboard)
+    }
+
+    /// Process a single request line, and return the reply to send back.
+    pub fn process(&mut self, line: &str) -> Result<Vec<u8>> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+
+        let mut lines = vec![];
+        if command == "help" {
+            lines.extend(self.settings.keys().cloned());
+            lines.push("help".to_string());
+            lines.sort();
+        } else if args.is_empty() {
+            match self.settings.get(command) {
+                Some(Setting::Value(v)) => lines.push(v.clone()),
+                Some(Setting::Storage(values)) => lines.push(join(values)),
+                None => {}
+            }
+        } else if let Some(setting) = self.settings.get_mut(command) {
+            match setting {
+                Setting::Value(v) => *v = args.join(" "),
+                Setting::Storage(values) => {
+                    for (value, arg) in values.iter_mut().zip(&args) {
+                        *value = arg.parse().unwrap_or(*value);
+                    }
+                }
+            }
+            self.save()?;
+        }
+
+        let mut reply = vec![];
+        for line in lines {
+            reply.extend(line.as_bytes());
+            reply.extend(b"\r\n");
+        }
+        reply.extend(b".\r\n");
+        Ok(reply)
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            fs::write(path, serde_json::to_string_pretty(self)?)
+                .with_context(|| format!("Unable to write {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+fn prefixed(prefix: &[u32], fill: u32, size: usize) -> Vec<u32> {
+    let mut values = vec![fill; size];
+    values[..prefix.len()].copy_from_slice(prefix);
+    values
+}
+
+fn join(values: &[u32]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(keyboard: &mut Keyboard, line: &str) -> String {
+        String::from_utf8(keyboard.process(line).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn values_are_read_and_replaced() {
+        let mut keyboard = Keyboard::new(Model::Model100);
+        assert_eq!(reply(&mut keyboard, "led.brightness\n"), "160\r\n.\r\n");
+
+        assert_eq!(reply(&mut keyboard, "led.brightness 100\n"), ".\r\n");
+        assert_eq!(reply(&mut keyboard, "led.brightness\n"), "100\r\n.\r\n");
+    }
+
+    #[test]
+    fn storage_is_written_from_the_start() {
+        let mut keyboard = Keyboard::new(Model::Atreus);
+        let size = TAPDANCE_SIZE;
+
+        reply(&mut keyboard, "tapdance.map 1 x 3\n");
+        let values = reply(&mut keyboard, "tapdance.map\n");
+        let values: Vec<&str> = values.lines().next().unwrap().split(' ').collect();
+        assert_eq!(values.len(), size);
+        // Values that do not parse, and those beyond the ones written are left
+        // untouched.
+        assert_eq!(values[..4], ["1", "0", "3", "65535"]);
+
+        let args = vec!["7"; size + 1].join(" ");
+        reply(&mut keyboard, &format!("tapdance.map {}\n", args));
+        let values = reply(&mut keyboard, "tapdance.map\n");
+        assert_eq!(
+            values.split_whitespace().filter(|&v| v == "7").count(),
+            size
+        );
+    }
+
+    #[test]
+    fn unknown_commands_have_empty_replies() {
+        let mut keyboard = Keyboard::new(Model::Model100);
+        assert_eq!(reply(&mut keyboard, "hardware.reboot\n"), ".\r\n");
+        assert_eq!(reply(&mut keyboard, "hardware.reboot 1\n"), ".\r\n");
+        assert_eq!(reply(&mut keyboard, "  \n"), ".\r\n");
+    }
+
+    #[test]
+    fn help_lists_the_commands_of_the_model() {
+        let help = |model| {
+            let reply = reply(&mut Keyboard::new(model), "help\n");
+            let lines: Vec<String> = reply.lines().map(String::from).collect();
+            assert_eq!(lines.last().unwrap(), ".");
+            lines[..lines.len() - 1].to_vec()
+        };
+
+        let model100 = help(Model::Model100);
+        assert!(model100.windows(2).all(|w| w[0] < w[1]));
+        assert!(model100.contains(&"help".to_string()));
+        assert!(model100.contains(&"colormap.map".to_string()));
+
+        let atreus = help(Model::Atreus);
+        assert!(atreus.contains(&"keymap.custom".to_string()));
+        assert!(!atreus
+            .iter()
+            .any(|c| c.starts_with("led") || c == "palette"));
+    }
+
+    #[test]
+    fn state_is_saved_after_every_change() {
+        let path = std::env::temp_dir().join(format!("focus-emulator-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut keyboard = Keyboard::load_or_new(Model::Model100, path.clone()).unwrap();
+        assert!(path.exists());
+        reply(&mut keyboard, "led.brightness 42\n");
+
+        let mut reloaded = Keyboard::load_or_new(Model::Atreus, path.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reply(&mut reloaded, "led.brightness\n"), "42\r\n.\r\n");
+    }
+}
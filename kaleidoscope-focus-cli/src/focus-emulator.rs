@@ -0,0 +1,150 @@
+// focus-emulator -- emulate a keyboard speaking Focus
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use clap::Parser;
+use kaleidoscope_focus_cli::emulator::Model;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Emulate a keyboard speaking Focus, on a pseudo-terminal"
+)]
+struct Options {
+    #[arg(short, long, value_enum, default_value = "model100")]
+    /// The keyboard model to emulate
+    model: Model,
+
+    #[arg(short, long, value_name = "FILE")]
+    /// Keep the state of the keyboard in a file, so it survives restarts
+    state: Option<PathBuf>,
+
+    #[arg(long, value_name = "MS", default_value = "0")]
+    /// Wait this many milliseconds before replying to a request
+    delay: u64,
+
+    #[arg(long, value_name = "N")]
+    /// Do not reply to every Nth request
+    drop_every: Option<usize>,
+
+    #[arg(long, value_name = "N")]
+    /// Hang up after N requests
+    disconnect_after: Option<usize>,
+}
+
+#[cfg(unix)]
+fn main() {
+    if let Err(e) = pty::run(Options::parse()) {
+        eprintln!("Error: {:#}", e);
+        ::std::process::exit(1);
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    Options::parse();
+    eprintln!("Error: The emulator is only supported on Unix systems");
+    ::std::process::exit(1);
+}
+
+#[cfg(unix)]
+mod pty {
+    use super::Options;
+    use anyhow::{bail, Result};
+    use kaleidoscope_focus_cli::emulator::Keyboard;
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+    use std::time::Duration;
+
+    pub fn run(opts: Options) -> Result<()> {
+        let mut keyboard = match opts.state {
+            Some(path) => Keyboard::load_or_new(opts.model, path)?,
+            None => Keyboard::new(opts.model),
+        };
+        let (mut master, path) = open()?;
+
+        println!("{}", path);
+        io::stdout().flush()?;
+
+        let mut input = vec![];
+        let mut buffer = [0; 1024];
+        let mut requests = 0;
+        loop {
+            let n = master.read(&mut buffer)?;
+            input.extend(&buffer[..n]);
+
+            while let Some(pos) = input.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = input.drain(..=pos).collect();
+                let reply = keyboard.process(&String::from_utf8_lossy(&line))?;
+                requests += 1;
+
+                // Dropped requests count towards the disconnect too.
+                let dropped = opts
+                    .drop_every
+                    .map_or(false, |n| n > 0 && requests % n == 0);
+                if !dropped {
+                    thread::sleep(Duration::from_millis(opts.delay));
+                    master.write_all(&reply)?;
+                }
+
+                if opts.disconnect_after == Some(requests) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Open a pseudo-terminal in raw mode, and return its master side, along
+    /// with the path to its slave side.
+    fn open() -> Result<(File, String)> {
+        unsafe {
+            let (mut master, mut slave) = (0, 0);
+            if libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            ) != 0
+            {
+                bail!(
+                    "Unable to open a pseudo-terminal: {}",
+                    io::Error::last_os_error()
+                );
+            }
+
+            let mut termios: libc::termios = std::mem::zeroed();
+            libc::tcgetattr(slave, &mut termios);
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave, libc::TCSANOW, &termios);
+
+            let name = libc::ttyname(slave);
+            if name.is_null() {
+                bail!(
+                    "Unable to name the pseudo-terminal: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            let path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            // The slave side is deliberately kept open - and leaked -, so that
+            // reads from the master do not fail between client connections.
+            Ok((File::from_raw_fd(master), path))
+        }
+    }
+}
@@ -14,9 +14,8 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use clap::Parser;
-
-mod shared;
-use crate::shared::{Cli, ConnectionOptions};
+use kaleidoscope_focus_cli::shared::{Cli, ConnectionOptions, DeviceSelection};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -25,6 +24,14 @@ struct Options {
     /// The device to connect to
     device: Option<String>,
 
+    #[command(flatten)]
+    select: DeviceSelection,
+
+    #[arg(long, value_name = "FILE")]
+    /// Write every chunk of data sent to, or received from the keyboard into
+    /// a capture file
+    trace: Option<PathBuf>,
+
     /// The command to send
     command: String,
     /// Optional arguments for <COMMAND>
@@ -34,12 +41,18 @@ struct Options {
 fn main() {
     let opts = Options::parse();
 
-    let mut cli = Cli::connect(ConnectionOptions {
+    let result = Cli::connect(ConnectionOptions {
         device: opts.device,
+        select: opts.select,
         chunk_size: 32,
         quiet: true,
-    });
+        trace: opts.trace,
+        reconnect: false,
+    })
+    .and_then(|mut cli| cli.send(&opts.command, &opts.args));
 
-    cli.send(&opts.command, &opts.args)
-        .expect("Error communicating with the keyboard");
+    if let Err(e) = result {
+        eprintln!("Error: {:#}", e);
+        ::std::process::exit(1);
+    }
 }
@@ -14,9 +14,9 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use clap::{Args, Parser, Subcommand};
-
-mod shared;
-use crate::shared::{Cli, ConnectionOptions};
+use kaleidoscope_focus::devices::Selector;
+use kaleidoscope_focus_cli::shared::{self, Cli, ConnectionOptions, RestoreOptions, Selection};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -29,13 +29,60 @@ struct Options {
 #[command(version, about)]
 enum Commands {
     /// List available ports for focus-capable devices
-    ListPorts,
+    ListPorts(ListPorts),
     /// Send a request to the keyboard, and display the reply
     Send(Send),
     /// Create a backup of the keyboards configuration
-    Backup(ConnectionOptions),
+    Backup(Backup),
     /// Restore the keyboards configuration from backup
-    Restore(ConnectionOptions),
+    Restore(Restore),
+    /// Start an interactive session with the keyboard
+    Shell(ConnectionOptions),
+    /// Wait for a supported device to be connected, or disconnected
+    Wait(Wait),
+    /// Report supported devices as they are connected and disconnected
+    WatchDevices(WatchDevices),
+}
+
+#[derive(Args)]
+pub struct ListPorts {
+    #[arg(long)]
+    /// Print the devices as JSON, rather than a table
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct All {
+    #[arg(long, conflicts_with_all = ["device", "serial", "index", "trace"])]
+    /// Operate on every supported device - those matching `--model`, if given -
+    /// in parallel
+    pub all: bool,
+}
+
+#[derive(Args)]
+pub struct Wait {
+    #[arg(long)]
+    /// Wait for the device with this serial number
+    pub serial: Option<String>,
+
+    #[arg(long)]
+    /// Wait for a device whose name contains MODEL, ignoring case
+    pub model: Option<String>,
+
+    #[arg(long)]
+    /// Wait for the device to be disconnected, rather than connected
+    pub gone: bool,
+
+    #[arg(long, value_name = "SECS")]
+    /// Give up after this many seconds
+    pub timeout: Option<u64>,
+}
+
+#[derive(Args)]
+pub struct WatchDevices {
+    #[arg(long)]
+    /// Print the events as JSON, one per line
+    pub json: bool,
 }
 
 #[derive(Args)]
@@ -43,20 +90,83 @@ pub struct Send {
     #[command(flatten)]
     pub shared: ConnectionOptions,
 
+    #[command(flatten)]
+    pub all: All,
+
     /// The command to send
     pub command: String,
     /// Optional arguments for <COMMAND>
     pub args: Vec<String>,
 }
 
+#[derive(Args)]
+pub struct Backup {
+    #[command(flatten)]
+    pub shared: ConnectionOptions,
+
+    #[command(flatten)]
+    pub all: All,
+
+    #[arg(long, value_name = "DIR", default_value = ".", requires = "all")]
+    /// With `--all`, the directory to write the backups to, one file per
+    /// device, named after its serial number
+    pub output_dir: PathBuf,
+
+    #[command(flatten)]
+    pub selection: Selection,
+}
+
+#[derive(Args)]
+pub struct Restore {
+    #[command(flatten)]
+    pub shared: ConnectionOptions,
+
+    #[command(flatten)]
+    pub all: All,
+
+    #[command(flatten)]
+    pub options: RestoreOptions,
+}
+
 fn main() {
     let opts = Options::parse();
 
-    match opts.command {
-        Commands::ListPorts => Cli::list_ports(),
-        Commands::Send(s) => Cli::connect(s.shared).send(&s.command, &s.args),
-        Commands::Backup(o) => Cli::connect(o).backup(),
-        Commands::Restore(o) => Cli::connect(o).restore(),
+    let result = match opts.command {
+        Commands::ListPorts(l) => Cli::list_ports(l.json),
+        Commands::Send(s) if s.all.all => {
+            Cli::for_all(s.shared, move |cli, _| cli.request(&s.command, &s.args))
+        }
+        Commands::Send(s) => {
+            Cli::connect(s.shared).and_then(|mut cli| cli.send(&s.command, &s.args))
+        }
+        Commands::Backup(b) if b.all.all => Cli::for_all(b.shared, move |cli, device| {
+            cli.backup_to_dir(&b.selection, device, &b.output_dir)
+                .map(|path| format!("saved to {}", path.display()))
+        }),
+        Commands::Backup(b) => Cli::connect(b.shared).and_then(|mut cli| cli.backup(&b.selection)),
+        Commands::Restore(r) if r.all.all => shared::read_backup().and_then(|backup| {
+            Cli::for_all(r.shared, move |cli, _| {
+                cli.restore_backup(&backup, &r.options)
+                    .map(|_| String::new())
+            })
+        }),
+        Commands::Restore(r) => Cli::connect(r.shared).and_then(|mut cli| cli.restore(&r.options)),
+        Commands::Shell(o) => Cli::connect(o).and_then(|mut cli| cli.shell()),
+        Commands::Wait(w) => {
+            let mut selector = Selector::new();
+            if let Some(serial) = &w.serial {
+                selector = selector.serial(serial);
+            }
+            if let Some(model) = &w.model {
+                selector = selector.model(model);
+            }
+            Cli::wait(&selector, w.gone, w.timeout)
+        }
+        Commands::WatchDevices(w) => Cli::watch_devices(w.json),
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {:#}", e);
+        ::std::process::exit(1);
     }
-    .expect("Error communicating with the keyboard");
 }
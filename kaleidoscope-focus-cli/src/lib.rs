@@ -0,0 +1,26 @@
+// focus -- focus interaction tool
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The code shared between the `focus`, `focus-send` and `focus-emulator`
+//! binaries. It is not meant to be used outside of this crate, and makes no
+//! promises about its stability.
+
+#![doc(hidden)]
+
+pub mod backup;
+pub mod config;
+pub mod emulator;
+pub mod shared;
+pub mod shell;
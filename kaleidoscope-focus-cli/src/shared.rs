@@ -13,13 +13,21 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use anyhow::Result;
+use crate::backup::{self, BackupData, DeviceIdentity};
+use crate::config;
+use crate::shell::{self, Editor};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Args;
-use indicatif::{ProgressBar, ProgressStyle};
-use kaleidoscope_focus::Focus;
-use serde::{Deserialize, Serialize};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use kaleidoscope_focus::devices::{DeviceEvent, DeviceInfo, Registry, Selector};
+use kaleidoscope_focus::{trace, Error, Focus, ReconnectPolicy};
 use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Args)]
 pub struct ConnectionOptions {
@@ -27,6 +35,9 @@ pub struct ConnectionOptions {
     /// The device to connect to
     pub device: Option<String>,
 
+    #[command(flatten)]
+    pub select: DeviceSelection,
+
     #[arg(short, long, default_value = "32")]
     /// Set the size of the buffer used to send data. Setting it to 0 writes
     /// everything all at once
@@ -35,35 +46,212 @@ pub struct ConnectionOptions {
     #[arg(short, long, default_value = "false")]
     /// Operate quietly
     pub quiet: bool,
+
+    #[arg(long, value_name = "FILE")]
+    /// Write every chunk of data sent to, or received from the keyboard into
+    /// a capture file
+    pub trace: Option<PathBuf>,
+
+    #[arg(long)]
+    /// If the keyboard resets, wait for it to come back, and retry reading
+    /// the setting that was being read
+    pub reconnect: bool,
+}
+
+/// Options selecting one of the auto-detected devices.
+#[derive(Args)]
+pub struct DeviceSelection {
+    #[arg(long, conflicts_with = "device")]
+    /// Connect to the device with this serial number
+    pub serial: Option<String>,
+
+    #[arg(long, conflicts_with = "device")]
+    /// Connect to the device whose name contains MODEL, ignoring case
+    pub model: Option<String>,
+
+    #[arg(long, conflicts_with = "device")]
+    /// Connect to the INDEXth matching device, counting from zero, in the order
+    /// `list-ports` lists them
+    pub index: Option<usize>,
+}
+
+impl DeviceSelection {
+    pub fn selector(&self) -> Selector {
+        let mut selector = Selector::new();
+        if let Some(serial) = &self.serial {
+            selector = selector.serial(serial);
+        }
+        if let Some(model) = &self.model {
+            selector = selector.model(model);
+        }
+        if let Some(index) = self.index {
+            selector = selector.index(index);
+        }
+        selector
+    }
+}
+
+#[derive(Args)]
+pub struct RestoreOptions {
+    #[arg(long)]
+    /// Compare the backup with the keyboard, and display the differences,
+    /// without restoring anything
+    pub dry_run: bool,
+
+    #[arg(long)]
+    /// Only restore the settings that differ from those on the keyboard
+    pub changed_only: bool,
+
+    #[command(flatten)]
+    pub selection: Selection,
+}
+
+#[derive(Args)]
+pub struct Selection {
+    #[arg(long, value_name = "PATTERN")]
+    /// Only include commands matching the glob pattern. Can be given multiple
+    /// times. Patterns starting with `!` exclude commands instead
+    pub include: Vec<String>,
+
+    #[arg(long, value_name = "PATTERN")]
+    /// Exclude commands matching the glob pattern. Can be given multiple times
+    pub exclude: Vec<String>,
+}
+
+impl Selection {
+    /// Return whether a command is selected: it matches one of the included
+    /// patterns - if there are any -, and none of the excluded ones.
+    pub fn matches(&self, command: &str) -> bool {
+        let (excludes, includes): (Vec<&str>, Vec<&str>) = self
+            .include
+            .iter()
+            .map(|p| p.as_str())
+            .partition(|p| p.starts_with('!'));
+        let excludes = excludes
+            .iter()
+            .map(|p| &p[1..])
+            .chain(self.exclude.iter().map(|p| p.as_str()));
+
+        (includes.is_empty() || includes.iter().any(|p| glob_match(p, command)))
+            && !excludes.into_iter().any(|p| glob_match(p, command))
+    }
 }
 
 pub struct Cli {
     conn: Focus,
     progress: ProgressBar,
+    registry: Registry,
+    /// Whether the tool is operating on several devices at once, and should
+    /// say which one its output is about.
+    batch: bool,
 }
 
-#[allow(dead_code)]
 impl Cli {
-    pub fn connect(opts: ConnectionOptions) -> Self {
+    pub fn connect(opts: ConnectionOptions) -> Result<Self> {
+        let registry = config::registry()?;
         let device_path = match &opts.device {
             Some(d) => d.to_string(),
-            None => Focus::find_devices()
-                .expect("No supported device found")
-                .remove(0),
+            None => registry.select(&opts.select.selector())?.port,
         };
-
-        let mut conn = Focus::create(&device_path)
-            .chunk_size(opts.chunk_size)
-            .open()
-            .unwrap_or_else(|e| {
-                eprintln!("Failed to open \"{}\". Error: {}", &device_path, e);
-                ::std::process::exit(1);
-            });
         let progress = if opts.quiet {
             ProgressBar::hidden()
         } else {
             ProgressBar::new(0)
         };
+
+        Self::open(&opts, &device_path, registry, progress)
+    }
+
+    /// Run an operation against every supported device - those matching
+    /// `--model`, if given - in parallel, each with its own progress bar. Once
+    /// all of them finished, display how each fared, along with the message
+    /// the operation returned.
+    pub fn for_all<F>(opts: ConnectionOptions, op: F) -> Result<()>
+    where
+        F: Fn(&mut Cli, &DeviceInfo) -> Result<String> + Send + Sync + 'static,
+    {
+        let registry = config::registry()?;
+        let selector = opts.select.selector();
+        let devices: Vec<DeviceInfo> = registry
+            .find_devices()?
+            .into_iter()
+            .filter(|d| selector.matches(d))
+            .collect();
+        if devices.is_empty() {
+            return Err(Error::NoDevice(selector.to_string()).into());
+        }
+
+        let multi = if opts.quiet {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        } else {
+            MultiProgress::new()
+        };
+        let opts = Arc::new(opts);
+        let op = Arc::new(op);
+        let threads: Vec<_> = devices
+            .into_iter()
+            .map(|device| {
+                let progress = multi.add(ProgressBar::new(0));
+                let (opts, op, registry) = (opts.clone(), op.clone(), registry.clone());
+                let info = device.clone();
+                let thread = thread::spawn(move || {
+                    let mut cli = Self::open(&opts, &device.port, registry, progress)?;
+                    cli.batch = true;
+                    op(&mut cli, &device)
+                });
+                (info, thread)
+            })
+            .collect();
+        let results: Vec<(DeviceInfo, Result<String>)> = threads
+            .into_iter()
+            .map(|(device, thread)| {
+                let result = thread
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("the operation crashed")));
+                (device, result)
+            })
+            .collect();
+        multi.clear()?;
+
+        let mut failed = 0;
+        for (device, result) in &results {
+            match result {
+                Ok(message) if message.is_empty() => println!("{}: ok", device),
+                Ok(message) => println!("{}: ok: {}", device, message),
+                Err(e) => {
+                    failed += 1;
+                    println!("{}: failed: {:#}", device, e);
+                }
+            }
+        }
+        if failed > 0 {
+            bail!("{} of {} devices failed", failed, results.len());
+        }
+        Ok(())
+    }
+
+    fn open(
+        opts: &ConnectionOptions,
+        device_path: &str,
+        registry: Registry,
+        progress: ProgressBar,
+    ) -> Result<Self> {
+        let mut builder = Focus::create(device_path).chunk_size(opts.chunk_size);
+        if opts.reconnect {
+            builder = builder.reconnect(ReconnectPolicy::new().registry(registry.clone()));
+        }
+        let mut conn = builder.open()?;
+        if let Some(path) = &opts.trace {
+            let file = File::create(path)
+                .with_context(|| format!("Unable to create {}", path.display()))?;
+            conn.set_trace(trace::capture(file));
+        }
+
+        Ok(Self::new(conn, registry, progress))
+    }
+
+    /// Operate on an open connection, reporting progress on `progress`.
+    pub fn new(mut conn: Focus, registry: Registry, progress: ProgressBar) -> Self {
         progress.set_style(ProgressStyle::with_template("{spinner} {prefix}{msg}").unwrap());
 
         let cloned_progress = progress.clone();
@@ -71,10 +259,25 @@ impl Cli {
             cloned_progress.inc(delta.try_into().unwrap());
         });
 
-        Self { conn, progress }
+        Self {
+            conn,
+            progress,
+            registry,
+            batch: false,
+        }
     }
 
     pub fn send(&mut self, command: &str, args: &[String]) -> Result<()> {
+        let reply = self.request(command, args)?;
+        if !reply.is_empty() {
+            println!("{}", reply);
+        }
+
+        Ok(())
+    }
+
+    /// Send a request to the keyboard, and return the reply.
+    pub fn request(&mut self, command: &str, args: &[String]) -> Result<String> {
         self.progress.set_prefix(format!(
             "sending `{}` (to {}): ",
             &command,
@@ -83,25 +286,133 @@ impl Cli {
 
         let reply = self.conn.flush()?.request(command, Some(args))?;
         self.progress.finish_and_clear();
+        Ok(reply)
+    }
 
-        if !reply.is_empty() {
-            println!("{}", reply);
+    pub fn list_ports(json: bool) -> Result<()> {
+        let devices = config::registry()?.find_devices()?;
+        let optional = |s: &Option<String>| s.clone().unwrap_or_else(|| "-".to_string());
+
+        if json {
+            let devices: Vec<serde_json::Value> = devices.iter().map(device_json).collect();
+            println!("{}", serde_json::to_string_pretty(&devices)?);
+            return Ok(());
         }
 
+        if devices.is_empty() {
+            bail!("No supported devices found");
+        }
+        let header = ["#", "PORT", "NAME", "VID:PID", "SERIAL", "MANUFACTURER"].map(String::from);
+        let rows: Vec<[String; 6]> = devices
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                [
+                    i.to_string(),
+                    d.port.clone(),
+                    d.name.clone(),
+                    format!("{:04x}:{:04x}", d.vid, d.pid),
+                    optional(&d.serial_number),
+                    optional(&d.manufacturer),
+                ]
+            })
+            .collect();
+        let mut widths = [0; 6];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        for row in std::iter::once(&header).chain(&rows) {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            println!("{}", cells.join("  ").trim_end());
+        }
         Ok(())
     }
 
-    pub fn list_ports() -> Result<()> {
-        Focus::find_devices()
-            .expect("No supported devices found")
-            .iter()
-            .for_each(|device| {
-                println!("{}", device);
-            });
+    /// Wait until a supported device matching `selector` is connected - or with
+    /// `gone`, until none is -, for at most `timeout` seconds. Prints the port
+    /// of the device once it is connected.
+    pub fn wait(selector: &Selector, gone: bool, timeout: Option<u64>) -> Result<()> {
+        let deadline = timeout.map(|t| Instant::now() + Duration::from_secs(t));
+        let mut watcher = config::registry()?.watch();
+        watcher.poll()?;
+
+        loop {
+            let mut matching = watcher.connected().iter().filter(|d| selector.matches(d));
+            match matching.next() {
+                None if gone => return Ok(()),
+                Some(device) if !gone => {
+                    println!("{}", device.port);
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            let left = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if watcher.next_event(left)?.is_none() {
+                let what = match selector.to_string() {
+                    s if s.is_empty() => "a supported device".to_string(),
+                    s => format!("a device matching {}", s),
+                };
+                let state = if gone { "disconnected" } else { "connected" };
+                bail!("Timed out waiting for {} to be {}", what, state);
+            }
+        }
+    }
+
+    /// Print supported devices as they are connected and disconnected, until
+    /// interrupted. Devices connected already are reported first.
+    pub fn watch_devices(json: bool) -> Result<()> {
+        for event in config::registry()?.watch() {
+            let (what, device) = match event? {
+                DeviceEvent::Arrived(device) => ("arrived", device),
+                DeviceEvent::Removed(device) => ("removed", device),
+            };
+            if json {
+                let mut value = device_json(&device);
+                value["event"] = what.into();
+                println!("{}", value);
+            } else {
+                println!("{} {}", what, device);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn backup(&mut self, selection: &Selection) -> Result<()> {
+        let backup = self.read_backup(selection)?;
+        println!("{}", serde_json::to_string(&backup)?);
         Ok(())
     }
 
-    pub fn backup(&mut self) -> Result<()> {
+    /// Back up the keyboard into a file in `dir`, named after its serial
+    /// number - or if it has none, its port -, and return the path to it.
+    pub fn backup_to_dir(
+        &mut self,
+        selection: &Selection,
+        device: &DeviceInfo,
+        dir: &Path,
+    ) -> Result<PathBuf> {
+        let name = match &device.serial_number {
+            Some(serial) => serial.clone(),
+            None => Path::new(&device.port)
+                .file_name()
+                .map_or_else(|| device.port.clone(), |n| n.to_string_lossy().into()),
+        };
+        let path = dir.join(format!("{}.json", name));
+
+        let backup = self.read_backup(selection)?;
+        fs::write(&path, serde_json::to_string(&backup)?)
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    fn read_backup(&mut self, selection: &Selection) -> Result<BackupData> {
         self.progress.set_prefix(format!(
             "backing up (from {}): ",
             &self.conn.port_name().unwrap()
@@ -156,10 +467,10 @@ impl Cli {
             ];
         }
 
-        let mut backup = BackupData {
-            commands: HashMap::new(),
-            restore: backup_commands.iter().map(|cmd| cmd.to_string()).collect(),
-        };
+        backup_commands.retain(|cmd| selection.matches(cmd));
+
+        let mut backup = BackupData::new();
+        backup.restore = backup_commands.iter().map(|cmd| cmd.to_string()).collect();
         for cmd in &backup_commands {
             self.progress.set_message(cmd.to_string());
             let reply = self.conn.command(cmd)?;
@@ -170,36 +481,476 @@ impl Cli {
             }
             self.progress.inc(1);
         }
+        let (device, firmware) = self.identify()?;
+        backup.seal(device, firmware);
         self.progress.finish_and_clear();
 
-        println!("{}", serde_json::to_string(&backup)?);
-        Ok(())
+        Ok(backup)
+    }
+
+    pub fn restore(&mut self, opts: &RestoreOptions) -> Result<()> {
+        self.restore_backup(&read_backup()?, opts)
     }
 
-    pub fn restore(&mut self) -> Result<()> {
-        let backup: BackupData =
-            serde_json::from_reader(io::stdin()).expect("Unable to parse the backup");
+    pub fn restore_backup(&mut self, backup: &BackupData, opts: &RestoreOptions) -> Result<()> {
+        let (device, firmware) = self.identify()?;
+        for mismatch in backup.mismatches(device.as_ref(), firmware.as_deref()) {
+            let warning = self.label(&format!("Warning: {}", mismatch));
+            self.progress.suspend(|| eprintln!("{}", warning));
+        }
 
         self.progress.set_prefix(format!(
             "restoring (to {}): ",
             &self.conn.port_name().unwrap()
         ));
 
+        // Take a snapshot of the settings we're about to restore, so that we
+        // can roll back if anything goes wrong.
+        let known = device.as_ref().and_then(|d| d.device(&self.registry));
+        let mut snapshot = HashMap::new();
+        let mut pending = vec![];
         for k in &backup.restore {
+            if !opts.selection.matches(k) {
+                continue;
+            }
             self.progress.set_message(k.clone());
             if let Some(v) = backup.commands.get(k) {
-                self.conn.request(k, Some(&[v.to_string()]))?;
+                let current = self.conn.command(k)?;
+                let changes = backup::diff(k, &current, v, known);
+                if opts.dry_run && !changes.is_empty() {
+                    let header = self.label(&format!("{}:", k));
+                    self.progress.suspend(|| {
+                        println!("{}", header);
+                        for change in &changes {
+                            println!("  {}", change);
+                        }
+                    });
+                }
+                if !opts.changed_only || !changes.is_empty() {
+                    pending.push((k.as_str(), v.as_str()));
+                }
+                snapshot.insert(k.as_str(), current);
+            }
+            self.progress.inc(1);
+        }
+        if opts.dry_run {
+            self.progress.finish_and_clear();
+            return Ok(());
+        }
+
+        let mut written = vec![];
+        let mut failures = vec![];
+        for (k, v) in pending {
+            self.progress.set_message(k.to_string());
+            written.push(k);
+            if let Err(e) = self.write_verified(k, v) {
+                failures.push(format!("{}: {:#}", k, e));
+                // Do not write anything else to a keyboard that went away. If
+                // it comes back - with `--reconnect` -, roll back what was
+                // written so far.
+                if is_disconnected(&e) {
+                    break;
+                }
+            }
+            self.progress.inc(1);
+        }
+        if failures.is_empty() {
+            self.progress.finish_and_clear();
+            return Ok(());
+        }
+
+        self.progress.set_prefix(format!(
+            "rolling back (on {}): ",
+            &self.conn.port_name().unwrap()
+        ));
+        let mut rollback_failures = vec![];
+        for k in written.into_iter().rev() {
+            self.progress.set_message(k.to_string());
+            let result = match snapshot.get(k).filter(|v| !v.is_empty()) {
+                Some(v) => self.write_verified(k, v),
+                None => Err(anyhow!("no previous value to roll back to")),
+            };
+            if let Err(e) = result {
+                rollback_failures.push(format!("{}: {:#}", k, e));
             }
             self.progress.inc(1);
         }
         self.progress.finish_and_clear();
 
+        let mut message = format!("Failed to restore:\n  {}", failures.join("\n  "));
+        if rollback_failures.is_empty() {
+            message.push_str("\nThe keyboard was rolled back to its previous state.");
+        } else {
+            message.push_str(&format!(
+                "\nFailed to roll back:\n  {}\nThe keyboard may be left partially restored.",
+                rollback_failures.join("\n  ")
+            ));
+        }
+        bail!(message)
+    }
+
+    /// Write a setting, then read it back to make sure the keyboard accepted it.
+    fn write_verified(&mut self, command: &str, value: &str) -> Result<()> {
+        self.conn.request(command, Some(&[value.to_string()]))?;
+        let readback = self.conn.command(command)?;
+        if !backup::equivalent(&readback, value) {
+            bail!("the keyboard did not accept the new value");
+        }
+        Ok(())
+    }
+
+    pub fn shell(&mut self) -> Result<()> {
+        // The progress indicator would only get in the way of the replies.
+        self.progress.set_draw_target(ProgressDrawTarget::hidden());
+
+        let port_name = self.conn.port_name().unwrap();
+        let commands = self
+            .conn
+            .flush()?
+            .capabilities()?
+            .commands()
+            .map(String::from)
+            .collect();
+        let device = self.registry.device_at(&port_name).ok().flatten();
+        let mut editor = Editor::new(commands);
+
+        println!(
+            "Connected to {}. Type `help` to list the supported commands, `exit` to quit.",
+            port_name
+        );
+        while let Some(line) = editor.read_line("focus> ")? {
+            let mut words = line.split_whitespace();
+            let command = match words.next() {
+                Some("exit" | "quit") => break,
+                Some(command) => command,
+                None => continue,
+            };
+            let args: Vec<String> = words.map(String::from).collect();
+
+            match self.conn.request(command, Some(&args)) {
+                Ok(reply) if reply.is_empty() => {}
+                Ok(reply) if args.is_empty() => {
+                    println!("{}", shell::pretty(command, &reply, device.as_ref()))
+                }
+                Ok(reply) => println!("{}", reply),
+                Err(e @ Error::Disconnected(_)) => return Err(e.into()),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+
         Ok(())
     }
+
+    /// Prefix a line of output with the port of the keyboard, when operating
+    /// on several at once.
+    fn label(&self, line: &str) -> String {
+        match self.conn.port_name().filter(|_| self.batch) {
+            Some(port) => format!("{}: {}", port, line),
+            None => line.to_string(),
+        }
+    }
+
+    /// Identify the connected keyboard, and the firmware running on it.
+    fn identify(&mut self) -> Result<(Option<DeviceIdentity>, Option<String>)> {
+        let device = self
+            .conn
+            .port_name()
+            .and_then(|name| self.registry.device_at(&name).ok().flatten())
+            .map(|d| DeviceIdentity::from(&d));
+        let firmware = Some(self.conn.command("version")?).filter(|v| !v.is_empty());
+        Ok((device, firmware))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct BackupData {
-    pub restore: Vec<String>,
-    pub commands: HashMap<String, String>,
+/// Read a backup from the standard input, and verify it.
+pub fn read_backup() -> Result<BackupData> {
+    let backup: BackupData =
+        serde_json::from_reader(io::stdin()).context("Unable to parse the backup")?;
+    backup.verify()?;
+    Ok(backup)
+}
+
+fn device_json(device: &DeviceInfo) -> serde_json::Value {
+    serde_json::json!({
+        "port": device.port,
+        "name": device.name,
+        "vid": format!("{:04x}", device.vid),
+        "pid": format!("{:04x}", device.pid),
+        "serial_number": device.serial_number,
+        "manufacturer": device.manufacturer,
+        "product": device.product,
+    })
+}
+
+fn is_disconnected(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<Error>(), Some(Error::Disconnected(_)))
+}
+
+/// Match a string against a glob pattern, where `*` matches any number of
+/// characters, and `?` matches exactly one.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let (pattern, s): (Vec<char>, Vec<char>) = (pattern.chars().collect(), s.chars().collect());
+    let (mut p, mut i) = (0, 0);
+    // Where the last `*` was, and the position in `s` it matched up to.
+    let mut backtrack = None;
+
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    i = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kaleidoscope_focus::testing::Replay;
+    use kaleidoscope_focus::trace::{Direction, TraceEvent};
+
+    /// Reading a setting, and the keyboard's reply.
+    fn read(command: &str, value: &str) -> (String, String) {
+        let reply = match value {
+            "" => ".\r\n".to_string(),
+            value => format!("{}\r\n.\r\n", value),
+        };
+        (format!("{} \n", command), reply)
+    }
+
+    /// Writing a setting, and the keyboard's reply.
+    fn write(command: &str, value: &str) -> (String, String) {
+        (format!("{} {}\n", command, value), ".\r\n".to_string())
+    }
+
+    fn replay(exchanges: &[(String, String)]) -> Replay {
+        let event = |direction, data: &String| TraceEvent {
+            elapsed: Duration::ZERO,
+            direction,
+            data: data.as_bytes().to_vec(),
+        };
+        Replay::new(exchanges.iter().flat_map(|(request, reply)| {
+            [
+                event(Direction::Sent, request),
+                event(Direction::Received, reply),
+            ]
+        }))
+    }
+
+    fn cli(replay: &Replay) -> Cli {
+        let conn = Focus::builder().interval(0).open_transport(replay.clone());
+        Cli::new(conn, Registry::empty(), ProgressBar::hidden())
+    }
+
+    fn everything() -> Selection {
+        Selection {
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+
+    fn restore_options(dry_run: bool) -> RestoreOptions {
+        RestoreOptions {
+            dry_run,
+            changed_only: false,
+            selection: everything(),
+        }
+    }
+
+    fn backup() -> BackupData {
+        let mut backup = BackupData::new();
+        for (command, value) in [("led.brightness", "200"), ("palette", "1 2 3")] {
+            backup.restore.push(command.to_string());
+            backup
+                .commands
+                .insert(command.to_string(), value.to_string());
+        }
+        backup
+    }
+
+    #[test]
+    fn backup_reads_what_the_keyboard_lists() {
+        let replay = replay(&[
+            read(" ", ""),
+            read("backup", "led.brightness\r\nkeymap.custom\r\nhostos.type"),
+            read("led.brightness", "160"),
+            read("keymap.custom", "1 2 3"),
+            read("hostos.type", ""),
+            read("version", "1.0"),
+        ]);
+        let backup = cli(&replay).read_backup(&everything()).unwrap();
+
+        assert!(replay.is_finished(), "{:?}", replay.divergence());
+        assert_eq!(backup.restore, ["led.brightness", "keymap.custom"]);
+        assert_eq!(backup.commands["keymap.custom"], "1 2 3");
+        assert_eq!(
+            backup.metadata.as_ref().and_then(|m| m.firmware.as_deref()),
+            Some("1.0")
+        );
+        backup.verify().unwrap();
+    }
+
+    #[test]
+    fn restore_writes_and_verifies_every_setting() {
+        let replay = replay(&[
+            read("version", ""),
+            read("led.brightness", "160"),
+            read("palette", "0 0 0"),
+            write("led.brightness", "200"),
+            read("led.brightness", "200"),
+            write("palette", "1 2 3"),
+            read("palette", "1 2 3"),
+        ]);
+        cli(&replay)
+            .restore_backup(&backup(), &restore_options(false))
+            .unwrap();
+        assert!(replay.is_finished(), "{:?}", replay.divergence());
+    }
+
+    #[test]
+    fn dry_run_does_not_write_anything() {
+        let replay = replay(&[
+            read("version", ""),
+            read("led.brightness", "160"),
+            read("palette", "0 0 0"),
+        ]);
+        cli(&replay)
+            .restore_backup(&backup(), &restore_options(true))
+            .unwrap();
+        assert!(replay.is_finished(), "{:?}", replay.divergence());
+    }
+
+    #[test]
+    fn failed_restore_is_rolled_back() {
+        let replay = replay(&[
+            read("version", ""),
+            read("led.brightness", "160"),
+            read("palette", "0 0 0"),
+            write("led.brightness", "200"),
+            read("led.brightness", "200"),
+            write("palette", "1 2 3"),
+            read("palette", "0 0 0"),
+            write("palette", "0 0 0"),
+            read("palette", "0 0 0"),
+            write("led.brightness", "160"),
+            read("led.brightness", "160"),
+        ]);
+        let error = cli(&replay)
+            .restore_backup(&backup(), &restore_options(false))
+            .unwrap_err()
+            .to_string();
+
+        assert!(replay.is_finished(), "{:?}", replay.divergence());
+        assert!(
+            error.contains("palette: the keyboard did not accept"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("rolled back to its previous state"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn failed_rollback_is_reported() {
+        let replay = replay(&[
+            read("version", ""),
+            read("led.brightness", "160"),
+            read("palette", "0 0 0"),
+            write("led.brightness", "200"),
+            read("led.brightness", "200"),
+            write("palette", "1 2 3"),
+            read("palette", "0 0 0"),
+            write("palette", "0 0 0"),
+            read("palette", "1 2 3"),
+            write("led.brightness", "160"),
+            read("led.brightness", "160"),
+        ]);
+        let error = cli(&replay)
+            .restore_backup(&backup(), &restore_options(false))
+            .unwrap_err()
+            .to_string();
+
+        assert!(replay.is_finished(), "{:?}", replay.divergence());
+        assert!(
+            error.contains("Failed to roll back:\n  palette"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("may be left partially restored"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn globs_match_whole_commands() {
+        assert!(glob_match("palette", "palette"));
+        assert!(!glob_match("palette", "palettes"));
+        assert!(!glob_match("palettes", "palette"));
+        assert!(glob_match("*", "keymap.custom"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("led.?rightness", "led.brightness"));
+        assert!(!glob_match("led.?", "led."));
+    }
+
+    #[test]
+    fn globs_match_around_stars() {
+        assert!(glob_match("keymap.*", "keymap.custom"));
+        assert!(glob_match("keymap.*", "keymap."));
+        assert!(!glob_match("keymap.*", "keymap"));
+        assert!(glob_match("*.map", "colormap.map"));
+        assert!(glob_match("*.map", "macros.map"));
+        assert!(!glob_match("*.map", "colormap.mapping"));
+        // The first `.map` is not the one the pattern ends with.
+        assert!(glob_match("*map", "colormap.map"));
+        assert!(glob_match("c*p.*p", "colormap.map"));
+        assert!(glob_match("k**.c?st*", "keymap.custom"));
+        assert!(!glob_match("*.map*x", "colormap.map"));
+    }
+
+    #[test]
+    fn selections_include_and_exclude_commands() {
+        let selection = |include: &[&str], exclude: &[&str]| Selection {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+        };
+
+        assert!(everything().matches("keymap.custom"));
+
+        let keymaps = selection(&["keymap.*"], &[]);
+        assert!(keymaps.matches("keymap.custom"));
+        assert!(!keymaps.matches("colormap.map"));
+
+        let negated = selection(&["!keymap.*"], &[]);
+        assert!(negated.matches("colormap.map"));
+        assert!(!negated.matches("keymap.custom"));
+
+        let mixed = selection(&["*.map", "!macros.*"], &[]);
+        assert!(mixed.matches("colormap.map"));
+        assert!(!mixed.matches("macros.map"));
+        assert!(!mixed.matches("palette"));
+
+        // Exclusions win over inclusions.
+        let both = selection(&["keymap.custom", "palette"], &["keymap.*"]);
+        assert!(!both.matches("keymap.custom"));
+        assert!(both.matches("palette"));
+    }
 }
@@ -0,0 +1,230 @@
+// focus -- focus interaction tool
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use kaleidoscope_focus::devices::DeviceInfo;
+use kaleidoscope_focus::keymap::Keymap;
+use kaleidoscope_focus::led::{Colormap, Palette};
+use kaleidoscope_focus::{macros, tapdance};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Context, Helper};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// The most lines of history to keep.
+const HISTORY_SIZE: usize = 1000;
+
+/// How many palette indexes to show per line, when printing a colormap.
+const LEDS_PER_LINE: usize = 16;
+
+/// A line editor, with history and completion of command names.
+pub struct Editor {
+    editor: rustyline::Editor<Commands>,
+    history_path: Option<PathBuf>,
+}
+
+impl Editor {
+    pub fn new(commands: Vec<String>) -> Self {
+        let config = Config::builder()
+            .max_history_size(HISTORY_SIZE)
+            .history_ignore_dups(true)
+            .auto_add_history(true)
+            .completion_type(CompletionType::List)
+            .build();
+        let mut editor = rustyline::Editor::with_config(config);
+        editor.set_helper(Some(Commands(commands)));
+
+        let history_path = history_path();
+        if let Some(path) = &history_path {
+            let _ = editor.load_history(path);
+        }
+
+        Self {
+            editor,
+            history_path,
+        }
+    }
+
+    /// Read a line, or return `None` at the end of the input. Interrupting
+    /// the line with Ctrl-C discards it, and reads another one.
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        loop {
+            match self.editor.readline(prompt) {
+                Ok(line) => {
+                    self.save_history();
+                    return Ok(Some(line));
+                }
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => return Ok(None),
+                Err(ReadlineError::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+        }
+    }
+
+    fn save_history(&mut self) {
+        // Failing to save the history is not worth interrupting the session for.
+        if let Some(path) = &self.history_path {
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            let _ = self.editor.append_history(path);
+        }
+    }
+}
+
+/// Completes the command names supported by the keyboard.
+struct Commands(Vec<String>);
+
+impl Completer for Commands {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, vec![]));
+        }
+        let candidates = self
+            .0
+            .iter()
+            .filter(|c| c.starts_with(prefix))
+            .cloned()
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Helper for Commands {}
+impl Hinter for Commands {
+    type Hint = String;
+}
+impl Highlighter for Commands {}
+impl Validator for Commands {}
+
+/// Where to keep the history: `$XDG_STATE_HOME/focus/history`, falling back to
+/// `~/.local/state/focus/history`, or `%APPDATA%\focus\history` on Windows.
+fn history_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("focus").join("history"))
+}
+
+/// Format the reply to a known command in a more readable way, using what is
+/// known about the device. Replies that are not known, or cannot be parsed are
+/// returned as-is.
+pub fn pretty(command: &str, reply: &str, device: Option<&DeviceInfo>) -> String {
+    let numbers =
+        || -> Option<Vec<u8>> { reply.split_whitespace().map(|n| n.parse().ok()).collect() };
+    let layers = |layers: Vec<String>| {
+        layers
+            .iter()
+            .enumerate()
+            .map(|(i, l)| format!("Layer {}:\n{}", i, l))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    let pretty = match command {
+        "keymap.custom" | "keymap.default" => device
+            .and_then(|d| d.geometry)
+            .and_then(|g| Keymap::parse(reply, g).ok())
+            .map(|keymap| {
+                layers(
+                    keymap
+                        .layers
+                        .iter()
+                        .map(|layer| {
+                            layer
+                                .rows()
+                                .map(|row| {
+                                    let keys: Vec<String> =
+                                        row.iter().map(|k| format!("{:>16}", k)).collect();
+                                    format!("  {}", keys.join(" "))
+                                })
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        })
+                        .collect(),
+                )
+            }),
+        "colormap.map" => device
+            .and_then(|d| d.leds)
+            .and_then(|leds| Colormap::parse(reply, leds).ok())
+            .map(|colormap| {
+                layers(
+                    colormap
+                        .layers
+                        .iter()
+                        .map(|layer| {
+                            layer
+                                .chunks(LEDS_PER_LINE)
+                                .map(|row| {
+                                    let leds: Vec<String> =
+                                        row.iter().map(|i| format!("{:>2}", i)).collect();
+                                    format!("  {}", leds.join(" "))
+                                })
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        })
+                        .collect(),
+                )
+            }),
+        "palette" => Palette::parse(reply).ok().map(|palette| {
+            palette
+                .0
+                .iter()
+                .enumerate()
+                .map(|(i, color)| format!("{:>2}: {}", i, color))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }),
+        "macros.map" => numbers()
+            .and_then(|bytes| macros::decode(&bytes).ok())
+            .map(|macros| {
+                macros
+                    .iter()
+                    .enumerate()
+                    .map(|(i, m)| format!("Macro {}: {}", i, m))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }),
+        "tapdance.map" => reply
+            .split_whitespace()
+            .map(|n| n.parse::<u16>().ok().map(From::from))
+            .collect::<Option<Vec<_>>>()
+            .map(|keys| {
+                tapdance::decode(&keys)
+                    .iter()
+                    .enumerate()
+                    .map(|(i, d)| format!("Tap-dance {}: {}", i, d))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }),
+        _ => None,
+    };
+
+    pretty.unwrap_or_else(|| reply.to_string())
+}
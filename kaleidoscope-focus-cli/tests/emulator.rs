@@ -0,0 +1,149 @@
+// focus -- focus interaction tool
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Runs the command-line tools against `focus-emulator`.
+
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+/// A running emulator, stopped when dropped.
+struct Emulator {
+    child: Child,
+    port: String,
+}
+
+impl Emulator {
+    /// Start the emulator, with `args`, to inject delays or faults.
+    fn start(args: &[&str]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_focus-emulator"))
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("unable to start the emulator");
+        let mut port = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut port)
+            .expect("unable to read the port of the emulator");
+
+        Self {
+            child,
+            port: port.trim().to_string(),
+        }
+    }
+
+    /// Run `focus` against the emulator, feeding it `input`.
+    fn run(&self, args: &[&str], input: &str) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_focus"))
+            .args(args)
+            .args(["--device", &self.port, "--quiet"])
+            .env("XDG_CONFIG_HOME", env!("CARGO_TARGET_TMPDIR"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("unable to run focus");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    /// Run `focus` against the emulator, and check that it succeeded.
+    fn focus(&self, args: &[&str], input: &str) -> Output {
+        let output = self.run(args, input);
+        assert!(
+            output.status.success(),
+            "focus {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    fn stdout(&self, args: &[&str], input: &str) -> String {
+        String::from_utf8(self.focus(args, input).stdout).unwrap()
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn send_backup_and_restore() {
+    let emulator = Emulator::start(&[]);
+
+    assert!(emulator
+        .stdout(&["send", "version"], "")
+        .starts_with("focus-emulator"));
+    assert_eq!(emulator.stdout(&["send", "led.brightness"], ""), "160\n");
+
+    let backup = emulator.stdout(&["backup"], "");
+    let json: serde_json::Value = serde_json::from_str(&backup).unwrap();
+    assert_eq!(json["commands"]["led.brightness"], "160");
+
+    // Nothing changed since the backup.
+    assert_eq!(emulator.stdout(&["restore", "--dry-run"], &backup), "");
+
+    emulator.focus(&["send", "led.brightness", "100"], "");
+    assert_eq!(
+        emulator.stdout(&["restore", "--dry-run"], &backup),
+        "led.brightness:\n  100 -> 160\n"
+    );
+    assert_eq!(emulator.stdout(&["send", "led.brightness"], ""), "100\n");
+
+    emulator.focus(&["send", "keymap.custom", "4", "5", "6"], "");
+    // Unchunked, lest writing every setting take several seconds.
+    emulator.focus(&["restore", "--chunk-size", "0"], &backup);
+    assert_eq!(emulator.stdout(&["send", "led.brightness"], ""), "160\n");
+    assert!(emulator
+        .stdout(&["send", "keymap.custom"], "")
+        .starts_with("0 0 0 0 "));
+    assert_eq!(emulator.stdout(&["restore", "--dry-run"], &backup), "");
+}
+
+#[test]
+fn slow_keyboards_are_waited_for() {
+    let emulator = Emulator::start(&["--delay", "200"]);
+    let started = Instant::now();
+
+    // A flush, and the request itself.
+    assert_eq!(emulator.stdout(&["send", "led.brightness"], ""), "160\n");
+    assert!(started.elapsed() >= Duration::from_millis(400));
+}
+
+#[test]
+fn disconnects_are_reported() {
+    let emulator = Emulator::start(&["--disconnect-after", "3"]);
+
+    // A flush, `backup`, and the first setting get replies, then the keyboard
+    // goes away.
+    let output = emulator.run(&["backup"], "");
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("disconnected"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,225 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::protocol::{self, ReplyReader};
+use crate::{Error, FocusBuilder, Result};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::{SerialPort, SerialStream};
+
+/// An asynchronous connection to a keyboard, built on [`tokio`].
+///
+/// The asynchronous counterpart of [`Focus`](crate::Focus), available when
+/// the `async` feature is enabled. Constructed using the same builder, with
+/// [`FocusBuilder::open_async`], or [`FocusBuilder::open_async_transport`] to
+/// talk to the keyboard over anything implementing [`AsyncRead`] and
+/// [`AsyncWrite`].
+///
+/// # Cancellation safety
+///
+/// Reading a reply is cancellation safe: if a request is dropped while
+/// waiting for its reply, the remainder of that reply is discarded before the
+/// next request is sent. Dropping a request while it is still being sent,
+/// however, may leave a partial command on the wire.
+///
+/// # Examples
+///
+/// ```
+/// # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), kaleidoscope_focus::Error> {
+/// # let keyboard = MockKeyboard::new().with_command("settings.version", "1");
+/// let mut conn = Focus::builder().interval(0).open_async_transport(keyboard);
+/// let reply = conn.command("settings.version").await?;
+/// assert_eq!(reply, "1");
+/// #   Ok(())
+/// # }
+/// ```
+pub struct AsyncFocus<T = SerialStream> {
+    transport: T,
+    chunk_size: usize,
+    interval: u64,
+    timeout: u64,
+    reader: ReplyReader,
+    unread_replies: usize,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncFocus<T> {
+    /// Send a request to the keyboard.
+    ///
+    /// See [`Focus::request`](crate::Focus::request).
+    pub async fn request(&mut self, command: &str, args: Option<&[String]>) -> Result<String> {
+        self.discard_unread().await?;
+        self.send(command, args).await?;
+        self.receive().await
+    }
+
+    /// Send a command - a request without arguments - to the keyboard.
+    ///
+    /// See [`Focus::command`](crate::Focus::command).
+    pub async fn command(&mut self, command: &str) -> Result<String> {
+        self.request(command, None).await
+    }
+
+    /// Flush any pending data.
+    ///
+    /// See [`Focus::flush`](crate::Focus::flush).
+    pub async fn flush(&mut self) -> Result<&mut Self> {
+        self.command(" ").await?;
+
+        // Discard anything else the keyboard may have sent, waiting no longer
+        // than a serial port would.
+        let mut buffer = [0; 1024];
+        let wait = Duration::from_millis(self.interval);
+        while let Ok(read) = tokio::time::timeout(wait, self.transport.read(&mut buffer)).await {
+            if read? == 0 {
+                break;
+            }
+        }
+        self.reader = ReplyReader::default();
+        Ok(self)
+    }
+
+    /// Return a reference to the underlying transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    async fn send(&mut self, command: &str, args: Option<&[String]>) -> Result<()> {
+        let request = protocol::format_request(command, args);
+
+        if self.chunk_size > 0 {
+            for c in request.as_bytes().chunks(self.chunk_size) {
+                self.transport.write_all(c).await?;
+                tokio::time::sleep(Duration::from_millis(self.interval)).await;
+            }
+        } else {
+            self.transport.write_all(request.as_bytes()).await?;
+        }
+        self.transport.flush().await?;
+        self.unread_replies += 1;
+
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<String> {
+        let timeout = Duration::from_millis(self.timeout);
+        tokio::time::timeout(timeout, self.read_reply())
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    async fn read_reply(&mut self) -> Result<String> {
+        let mut buffer = [0; 1024];
+
+        loop {
+            let t = self.transport.read(buffer.as_mut_slice()).await?;
+            if t == 0 {
+                return Err(Error::Disconnected(io::ErrorKind::UnexpectedEof.into()));
+            }
+            if let Some(reply) = self.reader.feed(&buffer[..t]) {
+                self.unread_replies -= 1;
+                return reply;
+            }
+        }
+    }
+
+    async fn discard_unread(&mut self) -> Result<()> {
+        while self.unread_replies > 0 {
+            match self.receive().await {
+                // The reply may not be valid, but we're throwing it away anyway.
+                Ok(_) | Err(Error::InvalidUtf8(_)) => {}
+                Err(e) => {
+                    self.unread_replies = 0;
+                    self.reader = ReplyReader::default();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FocusBuilder<'_> {
+    /// Open an asynchronous connection to the keyboard.
+    ///
+    /// Like [`FocusBuilder::open`], but returns an [`AsyncFocus`]. Must be
+    /// called from within a [`tokio`] runtime.
+    pub fn open_async(&self) -> Result<AsyncFocus> {
+        let device = self.device()?;
+        let mut port = SerialStream::open(
+            &tokio_serial::new(device, 115200).timeout(Duration::from_millis(self.interval)),
+        )
+        .map_err(|source| Error::Open {
+            device: device.to_string(),
+            source,
+        })?;
+        // Pseudo-terminals have no control signals, setting them is best-effort.
+        let _ = port.write_data_terminal_ready(true);
+
+        Ok(self.open_async_transport(port))
+    }
+
+    /// Open an asynchronous connection to the keyboard over a custom transport.
+    ///
+    /// Like [`FocusBuilder::open_transport`], but for [`AsyncFocus`].
+    pub fn open_async_transport<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        transport: T,
+    ) -> AsyncFocus<T> {
+        AsyncFocus {
+            transport,
+            chunk_size: self.chunk_size,
+            interval: self.interval,
+            timeout: self.timeout,
+            reader: ReplyReader::default(),
+            unread_replies: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Focus;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn flush_discards_stale_replies() {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let (reader, mut writer) = tokio::io::split(server);
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().is_empty() {
+                    // A reply to an earlier request arrives first, followed
+                    // by the reply to the flush.
+                    writer.write_all(b"stale\r\n.\r\n").await.unwrap();
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                    writer.write_all(b".\r\n").await.unwrap();
+                } else {
+                    writer.write_all(b"1\r\n.\r\n").await.unwrap();
+                }
+            }
+        });
+
+        let mut conn = Focus::builder()
+            .interval(20)
+            .chunk_size(0)
+            .open_async_transport(client);
+        conn.flush().await.unwrap();
+        assert_eq!(conn.command("settings.version").await.unwrap(), "1");
+    }
+}
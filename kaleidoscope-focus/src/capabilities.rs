@@ -0,0 +1,177 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{Error, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The set of Focus commands a keyboard supports.
+///
+/// Built from the reply to the `help` command, and grouped by plugin: the part
+/// of the command before the first `.` (`keymap` for `keymap.custom`).
+/// Commands without a `.` form a group of their own.
+///
+/// Usually obtained via [`Focus::capabilities`](crate::Focus::capabilities).
+///
+/// # Examples
+///
+/// ```
+/// # use kaleidoscope_focus::Capabilities;
+/// let caps = Capabilities::from_help("help\nkeymap.custom\nkeymap.default\npalette");
+///
+/// assert!(caps.supports("keymap.custom"));
+/// assert!(!caps.supports("colormap.map"));
+/// assert!(caps.has_plugin("keymap"));
+/// assert_eq!(
+///     caps.plugin_commands("keymap").collect::<Vec<_>>(),
+///     vec!["keymap.custom", "keymap.default"]
+/// );
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    plugins: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Capabilities {
+    /// Build the set of capabilities from the reply to a `help` command.
+    pub fn from_help(reply: &str) -> Self {
+        let mut plugins: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+        for command in reply.split_whitespace() {
+            plugins
+                .entry(Self::plugin_of(command).to_string())
+                .or_default()
+                .insert(command.to_string());
+        }
+
+        Self { plugins }
+    }
+
+    /// Return whether the keyboard supports the given command.
+    pub fn supports(&self, command: &str) -> bool {
+        self.plugins
+            .get(Self::plugin_of(command))
+            .map_or(false, |commands| commands.contains(command))
+    }
+
+    /// Return an error if the keyboard does not support the given command.
+    ///
+    /// Useful for failing early with [`Error::UnknownCommand`], rather than
+    /// sending a request, and interpreting an empty reply.
+    pub fn require(&self, command: &str) -> Result<()> {
+        if self.supports(command) {
+            Ok(())
+        } else {
+            Err(Error::UnknownCommand(command.to_string()))
+        }
+    }
+
+    /// Return whether the keyboard supports any command of the given plugin.
+    pub fn has_plugin(&self, plugin: &str) -> bool {
+        self.plugins.contains_key(plugin)
+    }
+
+    /// Iterate over the plugins the keyboard supports, in alphabetical order.
+    pub fn plugins(&self) -> impl Iterator<Item = &str> {
+        self.plugins.keys().map(|p| p.as_str())
+    }
+
+    /// Iterate over the supported commands of a plugin, in alphabetical order.
+    pub fn plugin_commands<'a>(&'a self, plugin: &str) -> impl Iterator<Item = &'a str> {
+        self.plugins
+            .get(plugin)
+            .into_iter()
+            .flatten()
+            .map(|c| c.as_str())
+    }
+
+    /// Iterate over every supported command, grouped by plugin.
+    pub fn commands(&self) -> impl Iterator<Item = &str> {
+        self.plugins.values().flatten().map(|c| c.as_str())
+    }
+
+    /// Return whether the set of capabilities is empty.
+    ///
+    /// This is the case for firmware that does not support the `help` command.
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    fn plugin_of(command: &str) -> &str {
+        command.split('.').next().unwrap_or(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELP: &str = "help\r\nkeymap.custom\r\nkeymap.default\r\nkeymap.onlyCustom\r\n\
+                        led.brightness\r\npalette\r\nsettings.valid?";
+
+    #[test]
+    fn commands_are_grouped_by_plugin() {
+        let caps = Capabilities::from_help(HELP);
+
+        assert_eq!(
+            caps.plugins().collect::<Vec<_>>(),
+            vec!["help", "keymap", "led", "palette", "settings"]
+        );
+        assert_eq!(
+            caps.plugin_commands("keymap").collect::<Vec<_>>(),
+            vec!["keymap.custom", "keymap.default", "keymap.onlyCustom"]
+        );
+        assert_eq!(
+            caps.plugin_commands("palette").collect::<Vec<_>>(),
+            vec!["palette"]
+        );
+        assert_eq!(caps.commands().count(), 7);
+        assert_eq!(caps.commands().nth(1), Some("keymap.custom"));
+    }
+
+    #[test]
+    fn missing_commands_and_plugins_are_not_supported() {
+        let caps = Capabilities::from_help(HELP);
+
+        assert!(caps.supports("settings.valid?"));
+        assert!(!caps.supports("keymap"));
+        assert!(!caps.supports("keymap.layerNames"));
+        assert!(!caps.supports("colormap.map"));
+        assert!(!caps.supports(""));
+        assert!(caps.has_plugin("led"));
+        assert!(!caps.has_plugin("colormap"));
+        assert!(!caps.has_plugin("keymap.custom"));
+        assert_eq!(caps.plugin_commands("colormap").count(), 0);
+    }
+
+    #[test]
+    fn requiring_missing_commands_fails() {
+        let caps = Capabilities::from_help(HELP);
+
+        assert!(caps.require("led.brightness").is_ok());
+        match caps.require("colormap.map") {
+            Err(Error::UnknownCommand(command)) => assert_eq!(command, "colormap.map"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn firmware_without_help_has_no_capabilities() {
+        let caps = Capabilities::from_help("");
+        assert!(caps.is_empty());
+        assert_eq!(caps, Capabilities::default());
+        assert!(caps.require("help").is_err());
+        assert!(!Capabilities::from_help(HELP).is_empty());
+    }
+}
@@ -0,0 +1,597 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The database of supported devices.
+//!
+//! A [`Registry`] knows the USB vendor and product IDs of supported keyboards,
+//! along with their name and - when known - their [`Geometry`] and number of
+//! LEDs. The default registry knows about Keyboardio's keyboards, and can be
+//! extended with any other Kaleidoscope-powered device.
+//!
+//! # Examples
+//!
+//! ```
+//! use kaleidoscope_focus::devices::{Device, Registry};
+//! use kaleidoscope_focus::keymap::Geometry;
+//!
+//! let mut registry = Registry::default();
+//! assert_eq!(registry.lookup(0x1209, 0x2303).unwrap().name, "Keyboardio Atreus");
+//!
+//! registry.add(Device::new("My Keyboard", 0x1234, 0x5678).with_geometry(Geometry { rows: 5, cols: 14 }));
+//! assert_eq!(
+//!     registry.lookup(0x1234, 0x5678).and_then(|d| d.geometry),
+//!     Some(Geometry { rows: 5, cols: 14 })
+//! );
+//! ```
+
+use crate::keymap::Geometry;
+use crate::{Error, Result};
+use std::collections::VecDeque;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A supported device.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Device {
+    /// The name of the device.
+    pub name: String,
+    /// The USB vendor ID.
+    pub vid: u16,
+    /// The USB product ID.
+    pub pid: u16,
+    /// The geometry of the keyboard, if known.
+    pub geometry: Option<Geometry>,
+    /// The number of LEDs - one per entry of a colormap layer - if known.
+    ///
+    /// Not necessarily the number of keys: some keyboards have underglow, or
+    /// keys without an LED.
+    pub leds: Option<usize>,
+}
+
+impl Device {
+    /// Create a device of unknown geometry and number of LEDs.
+    pub fn new(name: &str, vid: u16, pid: u16) -> Self {
+        Self {
+            name: name.to_string(),
+            vid,
+            pid,
+            geometry: None,
+            leds: None,
+        }
+    }
+
+    /// Set the geometry of the device.
+    pub fn with_geometry(mut self, geometry: Geometry) -> Self {
+        self.geometry = Some(geometry);
+        self
+    }
+
+    /// Set the number of LEDs of the device.
+    pub fn with_leds(mut self, leds: usize) -> Self {
+        self.leds = Some(leds);
+        self
+    }
+}
+
+/// A set of supported devices, identified by their USB vendor and product IDs.
+///
+/// The default registry contains the devices known to the crate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registry {
+    devices: Vec<Device>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry
+            .add(
+                Device::new("Keyboardio Model100", 0x3496, 0x0006)
+                    .with_geometry(Geometry::MODEL100)
+                    .with_leds(64),
+            )
+            .add(
+                Device::new("Keyboardio Atreus", 0x1209, 0x2303)
+                    .with_geometry(Geometry::ATREUS)
+                    .with_leds(0),
+            )
+            .add(
+                Device::new("Keyboardio Model01", 0x1209, 0x2301)
+                    .with_geometry(Geometry::MODEL01)
+                    .with_leds(64),
+            );
+        registry
+    }
+}
+
+impl Registry {
+    /// Create a registry without any devices.
+    pub fn empty() -> Self {
+        Self { devices: vec![] }
+    }
+
+    /// Add a device to the registry. A device already registered with the
+    /// same vendor and product IDs is replaced.
+    pub fn add(&mut self, device: Device) -> &mut Self {
+        self.devices
+            .retain(|d| (d.vid, d.pid) != (device.vid, device.pid));
+        self.devices.push(device);
+        self
+    }
+
+    /// Find a device by its USB vendor and product IDs.
+    pub fn lookup(&self, vid: u16, pid: u16) -> Option<&Device> {
+        self.devices.iter().find(|d| (d.vid, d.pid) == (vid, pid))
+    }
+
+    /// Iterate over the registered devices, in the order they were added.
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.devices.iter()
+    }
+
+    /// Find connected devices known to the registry.
+    ///
+    /// See [`Focus::find_devices`](crate::Focus::find_devices).
+    pub fn find_devices(&self) -> Result<Vec<DeviceInfo>> {
+        let ports = serialport::available_ports().map_err(Error::Enumerate)?;
+
+        Ok(ports
+            .into_iter()
+            .filter_map(|p| match p.port_type {
+                serialport::SerialPortType::UsbPort(info) => {
+                    let device = self.lookup(info.vid, info.pid)?;
+                    Some(DeviceInfo {
+                        port: p.port_name,
+                        name: device.name.clone(),
+                        vid: info.vid,
+                        pid: info.pid,
+                        serial_number: info.serial_number,
+                        manufacturer: info.manufacturer,
+                        product: info.product,
+                        geometry: device.geometry,
+                        leds: device.leds,
+                    })
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Find the connected device on the given port, if it is known to the
+    /// registry.
+    pub fn device_at(&self, port: &str) -> Result<Option<DeviceInfo>> {
+        Ok(self.find_devices()?.into_iter().find(|d| d.port == port))
+    }
+
+    /// Find the connected device matching a selector.
+    ///
+    /// Fails with [`Error::NoDevice`] if no device matches, and with
+    /// [`Error::AmbiguousDevice`] if several do, and the selector does not
+    /// pick one by index. This includes an empty selector, with several
+    /// devices connected.
+    pub fn select(&self, selector: &Selector) -> Result<DeviceInfo> {
+        selector.pick(&self.find_devices()?)
+    }
+
+    /// Watch for devices known to the registry arriving and going away.
+    pub fn watch(&self) -> Watcher {
+        Watcher::new(self.clone())
+    }
+}
+
+/// A connected device, as found by [`Registry::find_devices`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceInfo {
+    /// The path to the serial port of the device.
+    pub port: String,
+    /// The name of the device, as known to the registry.
+    pub name: String,
+    /// The USB vendor ID.
+    pub vid: u16,
+    /// The USB product ID.
+    pub pid: u16,
+    /// The USB serial number, if the device has one.
+    pub serial_number: Option<String>,
+    /// The manufacturer, as reported by the device.
+    pub manufacturer: Option<String>,
+    /// The product name, as reported by the device.
+    pub product: Option<String>,
+    /// The geometry of the keyboard, if known.
+    pub geometry: Option<Geometry>,
+    /// The number of LEDs of the keyboard, if known.
+    pub leds: Option<usize>,
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}", self.port, self.name)?;
+        if let Some(serial) = &self.serial_number {
+            write!(f, ", serial {}", serial)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Selects a device among the connected ones.
+///
+/// Displayed as a description of the selection, empty for an empty selector.
+///
+/// Devices can be selected by serial number, and by model: a device matches
+/// the model if its name contains it, ignoring case. Among the devices
+/// matching both, one can be picked by its index, starting from zero, in the
+/// order [`Registry::find_devices`] returns them.
+///
+/// ```no_run
+/// use kaleidoscope_focus::devices::{Registry, Selector};
+/// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+/// let device = Registry::default().select(&Selector::new().model("atreus").index(1))?;
+/// println!("The second Atreus is at {}", device.port);
+/// #   Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Selector {
+    serial: Option<String>,
+    model: Option<String>,
+    index: Option<usize>,
+}
+
+impl Selector {
+    /// Create a selector matching any device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match the device with the given serial number.
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.to_string());
+        self
+    }
+
+    /// Only match devices whose name contains `model`, ignoring case.
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    /// Pick the device with the given index among the matching ones.
+    pub fn index(mut self, index: usize) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    /// Return whether the selector matches any device.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Return whether a device matches the serial number and model of the
+    /// selector. The index is not taken into account.
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        let serial = self
+            .serial
+            .as_ref()
+            .map_or(true, |s| device.serial_number.as_ref() == Some(s));
+        let model = self.model.as_ref().map_or(true, |m| {
+            device.name.to_lowercase().contains(&m.to_lowercase())
+        });
+        serial && model
+    }
+
+    /// Pick the device matching the selector among `devices`.
+    ///
+    /// Fails like [`Registry::select`], which picks among the connected
+    /// devices.
+    pub fn pick(&self, devices: &[DeviceInfo]) -> Result<DeviceInfo> {
+        let mut candidates: Vec<DeviceInfo> = devices
+            .iter()
+            .filter(|d| self.matches(d))
+            .cloned()
+            .collect();
+
+        let index = match self.index {
+            Some(index) => index,
+            None if candidates.len() > 1 => {
+                return Err(Error::AmbiguousDevice {
+                    selection: self.to_string(),
+                    candidates,
+                })
+            }
+            None => 0,
+        };
+        if index >= candidates.len() {
+            return Err(Error::NoDevice(self.to_string()));
+        }
+        Ok(candidates.swap_remove(index))
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(serial) = &self.serial {
+            parts.push(format!("serial `{}`", serial));
+        }
+        if let Some(model) = &self.model {
+            parts.push(format!("model `{}`", model));
+        }
+        if let Some(index) = self.index {
+            parts.push(format!("index {}", index));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// A supported device arriving or going away, reported by a [`Watcher`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceEvent {
+    /// The device was connected.
+    Arrived(DeviceInfo),
+    /// The device was disconnected.
+    Removed(DeviceInfo),
+}
+
+/// Watches for supported devices arriving and going away.
+///
+/// The watcher polls the available serial ports, and reports the differences
+/// between two scans as [`DeviceEvent`]s. The first scan reports every device
+/// connected at the time as having arrived.
+///
+/// Used as an [`Iterator`], the watcher blocks until the next event.
+///
+/// ```no_run
+/// use kaleidoscope_focus::devices::{DeviceEvent, Registry};
+/// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+/// for event in Registry::default().watch() {
+///     match event? {
+///         DeviceEvent::Arrived(device) => println!("Hello, {}!", device),
+///         DeviceEvent::Removed(device) => println!("Goodbye, {}!", device),
+///     }
+/// }
+/// #   Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Watcher {
+    registry: Registry,
+    interval: Duration,
+    connected: Vec<DeviceInfo>,
+    pending: VecDeque<DeviceEvent>,
+}
+
+impl Watcher {
+    /// Create a watcher for the devices known to `registry`, polling every
+    /// half a second.
+    pub fn new(registry: Registry) -> Self {
+        Self {
+            registry,
+            interval: Duration::from_millis(500),
+            connected: vec![],
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Set the time between two scans, in milliseconds.
+    pub fn interval(mut self, interval: u64) -> Self {
+        self.interval = Duration::from_millis(interval);
+        self
+    }
+
+    /// Return the devices connected as of the last scan.
+    pub fn connected(&self) -> &[DeviceInfo] {
+        &self.connected
+    }
+
+    /// Scan the available serial ports once, and return what changed since the
+    /// previous scan.
+    pub fn poll(&mut self) -> Result<Vec<DeviceEvent>> {
+        let devices = self.registry.find_devices()?;
+        let events = changes(&self.connected, &devices);
+        self.connected = devices;
+        Ok(events)
+    }
+
+    /// Wait for the next event, for at most `timeout`, or forever if it is
+    /// `None`. Returns `None` if no event happened in time.
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> Result<Option<DeviceEvent>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+            let events = self.poll()?;
+            if !events.is_empty() {
+                self.pending.extend(events);
+                continue;
+            }
+
+            let mut sleep = self.interval;
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(None);
+                }
+                sleep = sleep.min(left);
+            }
+            thread::sleep(sleep);
+        }
+    }
+}
+
+/// Describe what changed between two scans: the devices that went away, then
+/// the ones that arrived. A device replaced by another on the same port is
+/// reported as both.
+fn changes(before: &[DeviceInfo], after: &[DeviceInfo]) -> Vec<DeviceEvent> {
+    let removed = before
+        .iter()
+        .filter(|d| !after.contains(d))
+        .cloned()
+        .map(DeviceEvent::Removed);
+    let arrived = after
+        .iter()
+        .filter(|d| !before.contains(d))
+        .cloned()
+        .map(DeviceEvent::Arrived);
+    removed.chain(arrived).collect()
+}
+
+impl Iterator for Watcher {
+    type Item = Result<DeviceEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event(None).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(port: &str, name: &str, serial: Option<&str>) -> DeviceInfo {
+        DeviceInfo {
+            port: port.to_string(),
+            name: name.to_string(),
+            vid: 0x3496,
+            pid: 0x0006,
+            serial_number: serial.map(String::from),
+            manufacturer: None,
+            product: None,
+            geometry: None,
+            leds: None,
+        }
+    }
+
+    fn devices() -> Vec<DeviceInfo> {
+        vec![
+            device("/dev/ttyACM0", "Keyboardio Model100", Some("A")),
+            device("/dev/ttyACM1", "Keyboardio Atreus", Some("B")),
+            device("/dev/ttyACM2", "Keyboardio Model100", None),
+        ]
+    }
+
+    #[test]
+    fn selectors_match_serial_numbers_and_models() {
+        let [model100, atreus, anonymous] = <[DeviceInfo; 3]>::try_from(devices()).unwrap();
+
+        assert!(Selector::new().matches(&anonymous));
+        assert!(Selector::new().serial("A").matches(&model100));
+        assert!(!Selector::new().serial("A").matches(&atreus));
+        assert!(!Selector::new().serial("A").matches(&anonymous));
+        assert!(Selector::new().model("model100").matches(&anonymous));
+        assert!(Selector::new().model("ATREUS").matches(&atreus));
+        assert!(!Selector::new()
+            .model("model100")
+            .serial("B")
+            .matches(&atreus));
+        // The index is not taken into account.
+        assert!(Selector::new().index(5).matches(&atreus));
+    }
+
+    #[test]
+    fn unique_matches_are_picked() {
+        let picked = Selector::new().serial("B").pick(&devices()).unwrap();
+        assert_eq!(picked.port, "/dev/ttyACM1");
+
+        let only = [device("/dev/ttyACM0", "Keyboardio Atreus", None)];
+        assert_eq!(Selector::new().pick(&only).unwrap(), only[0]);
+    }
+
+    #[test]
+    fn several_matches_are_ambiguous() {
+        for selector in [Selector::new(), Selector::new().model("model100")] {
+            match selector.pick(&devices()) {
+                Err(Error::AmbiguousDevice {
+                    selection,
+                    candidates,
+                }) => {
+                    assert_eq!(selection, selector.to_string());
+                    assert!(candidates.iter().all(|d| selector.matches(d)));
+                }
+                other => panic!("{:?} picked {:?}", selector, other),
+            }
+        }
+    }
+
+    #[test]
+    fn indexes_pick_among_the_matches() {
+        let selector = Selector::new().model("model100").index(1);
+        assert_eq!(selector.pick(&devices()).unwrap().port, "/dev/ttyACM2");
+        assert_eq!(
+            Selector::new().index(0).pick(&devices()).unwrap().port,
+            "/dev/ttyACM0"
+        );
+
+        assert!(matches!(
+            Selector::new().model("model100").index(2).pick(&devices()),
+            Err(Error::NoDevice(_))
+        ));
+    }
+
+    #[test]
+    fn nothing_matching_is_no_device() {
+        assert!(matches!(
+            Selector::new().serial("C").pick(&devices()),
+            Err(Error::NoDevice(_))
+        ));
+        assert!(matches!(Selector::new().pick(&[]), Err(Error::NoDevice(_))));
+    }
+
+    #[test]
+    fn first_scans_report_every_device_as_arrived() {
+        assert_eq!(
+            changes(&[], &devices()),
+            devices()
+                .into_iter()
+                .map(DeviceEvent::Arrived)
+                .collect::<Vec<_>>()
+        );
+        assert!(changes(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn unchanged_scans_report_nothing() {
+        assert!(changes(&devices(), &devices()).is_empty());
+    }
+
+    #[test]
+    fn scans_report_removals_then_arrivals() {
+        let before = devices();
+        let mut after = devices();
+        let atreus = after.remove(1);
+        let arrived = device("/dev/ttyACM3", "Keyboardio Model01", Some("C"));
+        after.push(arrived.clone());
+
+        assert_eq!(
+            changes(&before, &after),
+            vec![DeviceEvent::Removed(atreus), DeviceEvent::Arrived(arrived)]
+        );
+    }
+
+    #[test]
+    fn devices_replaced_on_the_same_port_are_removed_and_arrive() {
+        let before = devices();
+        let mut after = devices();
+        after[0] = device("/dev/ttyACM0", "Keyboardio Atreus", Some("D"));
+
+        assert_eq!(
+            changes(&before, &after),
+            vec![
+                DeviceEvent::Removed(before[0].clone()),
+                DeviceEvent::Arrived(after[0].clone()),
+            ]
+        );
+    }
+}
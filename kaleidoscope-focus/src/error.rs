@@ -0,0 +1,159 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::devices::DeviceInfo;
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+/// A specialized [`Result`](std::result::Result) type for Focus operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The errors that can happen while talking to a keyboard.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The device could not be opened.
+    Open {
+        /// The device we tried to open.
+        device: String,
+        /// The underlying error.
+        source: serialport::Error,
+    },
+    /// The available serial ports could not be listed.
+    Enumerate(serialport::Error),
+    /// No supported device matches the selection.
+    NoDevice(String),
+    /// Several devices match the selection.
+    AmbiguousDevice {
+        /// The selection the devices match.
+        selection: String,
+        /// The devices matching the selection.
+        candidates: Vec<DeviceInfo>,
+    },
+    /// The device went away: it was unplugged, or it reset.
+    Disconnected(io::Error),
+    /// The keyboard did not reply in time.
+    Timeout,
+    /// The keyboard does not support the requested command.
+    ///
+    /// Keyboards reply to unknown commands the same way as to commands without
+    /// any output, so [`Focus::request`](crate::Focus::request) and
+    /// [`Focus::command`](crate::Focus::command) never return this: they return
+    /// an empty reply. It is returned by
+    /// [`Capabilities::require`](crate::Capabilities::require), and by the
+    /// typed helpers reading a setting - such as
+    /// [`Focus::read_keymap`](crate::Focus::read_keymap) - when its reply is
+    /// empty. So are the helpers that read a setting before writing it, like
+    /// [`Focus::write_colormap`](crate::Focus::write_colormap).
+    UnknownCommand(String),
+    /// The reply from the keyboard was not valid UTF-8.
+    InvalidUtf8(FromUtf8Error),
+    /// The reply from the keyboard could not be interpreted.
+    InvalidReply {
+        /// The command whose reply could not be interpreted.
+        command: String,
+        /// What was wrong with the reply.
+        reason: String,
+    },
+    /// A value to be sent to the keyboard is not valid.
+    InvalidValue(String),
+    /// Any other I/O error.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Open { device, .. } => write!(f, "Failed to open \"{}\"", device),
+            Error::Enumerate(_) => write!(f, "Failed to list the serial ports"),
+            Error::NoDevice(selection) if selection.is_empty() => {
+                write!(f, "No supported device found")
+            }
+            Error::NoDevice(selection) => {
+                write!(f, "No supported device found matching {}", selection)
+            }
+            Error::AmbiguousDevice {
+                selection,
+                candidates,
+            } => {
+                if selection.is_empty() {
+                    write!(f, "Several supported devices found:")?;
+                } else {
+                    write!(f, "Several devices match {}:", selection)?;
+                }
+                for candidate in candidates {
+                    write!(f, "\n  {}", candidate)?;
+                }
+                Ok(())
+            }
+            Error::Disconnected(_) => write!(f, "The keyboard has been disconnected"),
+            Error::Timeout => write!(f, "Timed out waiting for a reply from the keyboard"),
+            Error::UnknownCommand(command) => {
+                write!(f, "The keyboard does not support the `{}` command", command)
+            }
+            Error::InvalidUtf8(_) => write!(f, "The reply from the keyboard is not valid UTF-8"),
+            Error::InvalidReply { command, reason } => {
+                write!(f, "Unexpected reply to `{}`: {}", command, reason)
+            }
+            Error::InvalidValue(reason) => write!(f, "Invalid value: {}", reason),
+            Error::Io(_) => write!(f, "Error communicating with the keyboard"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open { source, .. } | Error::Enumerate(source) => Some(source),
+            Error::Disconnected(e) | Error::Io(e) => Some(e),
+            Error::InvalidUtf8(e) => Some(e),
+            Error::Timeout
+            | Error::NoDevice(_)
+            | Error::AmbiguousDevice { .. }
+            | Error::UnknownCommand(_)
+            | Error::InvalidReply { .. }
+            | Error::InvalidValue(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        // On Unix-like systems, an unplugged serial device usually results in
+        // EIO, ENXIO or ENODEV, none of which have a dedicated `ErrorKind`.
+        #[cfg(unix)]
+        if let Some(5 | 6 | 19) = e.raw_os_error() {
+            return Error::Disconnected(e);
+        }
+
+        match e.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout,
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::NotFound
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::UnexpectedEof => Error::Disconnected(e),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Self {
+        Error::InvalidUtf8(e)
+    }
+}
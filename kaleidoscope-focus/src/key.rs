@@ -0,0 +1,1048 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decoding and encoding Kaleidoscope key codes.
+//!
+//! Kaleidoscope stores keys - in `keymap.custom`, `keymap.default`,
+//! `macros.map` and elsewhere - as 16-bit numbers. The [`Key`] type decodes
+//! these into structured variants with canonical names, modeled after the
+//! names used in Kaleidoscope sketches, and encodes them back.
+//!
+//! Every 16-bit value decodes into a [`Key`], and every key's name parses back
+//! into the same key:
+//!
+//! ```
+//! use kaleidoscope_focus::key::Key;
+//!
+//! for code in 0..=u16::MAX {
+//!     let key = Key::from(code);
+//!     assert_eq!(key.name().parse::<Key>().unwrap(), key);
+//!     assert_eq!(u16::try_from(key).unwrap(), code);
+//! }
+//! ```
+
+use crate::{Error, Result};
+use std::fmt;
+use std::ops::BitOr;
+use std::str::FromStr;
+
+const SYNTHETIC: u8 = 0b0100_0000;
+const RESERVED: u8 = 0b1000_0000;
+const IS_SYSCTL: u8 = 0b0000_0001;
+const IS_INTERNAL: u8 = 0b0000_0010;
+const SWITCH_TO_KEYMAP: u8 = 0b0000_0100;
+const IS_CONSUMER: u8 = 0b0000_1000;
+const IS_MOUSE_KEY: u8 = 0b0001_0000;
+const IS_MACRO: u8 = 0b0010_0000;
+const LED_TOGGLE: u8 = 0b0000_0001;
+
+const LAYER_SHIFT_OFFSET: u8 = 42;
+const LAYER_MOVE_OFFSET: u8 = 84;
+const KEYMAP_PREVIOUS: u8 = 33;
+const KEYMAP_NEXT: u8 = 34;
+
+/// The key ranges used by plugins, from Kaleidoscope's `Ranges.h`.
+mod ranges {
+    pub const FIRST: u16 = 0xC000;
+    pub const OSM_FIRST: u16 = 0xC001;
+    pub const OSM_LAST: u16 = OSM_FIRST + 7;
+    pub const OSL_FIRST: u16 = OSM_LAST + 1;
+    pub const OSL_LAST: u16 = OSL_FIRST + 7;
+    pub const DUM_FIRST: u16 = OSL_LAST + 1;
+    pub const DUM_LAST: u16 = DUM_FIRST + (8 << 8);
+    pub const DUL_FIRST: u16 = DUM_LAST + 1;
+    pub const DUL_LAST: u16 = DUL_FIRST + (8 << 8);
+    pub const TD_FIRST: u16 = DUL_LAST + 1;
+    pub const TD_LAST: u16 = TD_FIRST + 15;
+    pub const LEAD_FIRST: u16 = TD_LAST + 1;
+    pub const LEAD_LAST: u16 = LEAD_FIRST + 7;
+    pub const CYCLE: u16 = LEAD_LAST + 1;
+    pub const SYSTER: u16 = CYCLE + 1;
+    pub const TT_FIRST: u16 = SYSTER + 1;
+    pub const TT_LAST: u16 = TT_FIRST + 255;
+    pub const STENO_FIRST: u16 = TT_LAST + 1;
+    pub const STENO_LAST: u16 = STENO_FIRST + 42;
+    pub const SC_FIRST: u16 = STENO_LAST + 1;
+    pub const SC_LAST: u16 = SC_FIRST + 1;
+    pub const REDIAL: u16 = SC_LAST + 1;
+    pub const TURBO: u16 = REDIAL + 1;
+    pub const DYNAMIC_MACRO_FIRST: u16 = TURBO + 1;
+    pub const DYNAMIC_MACRO_LAST: u16 = DYNAMIC_MACRO_FIRST + 31;
+    pub const OS_META_STICKY: u16 = DYNAMIC_MACRO_LAST + 1;
+    pub const OS_ACTIVE_STICKY: u16 = OS_META_STICKY + 1;
+    pub const OS_CANCEL: u16 = OS_ACTIVE_STICKY + 1;
+    pub const CS_FIRST: u16 = OS_CANCEL + 1;
+    pub const CS_LAST: u16 = CS_FIRST + 63;
+}
+
+/// Modifier flags attached to a keyboard key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    /// No modifiers.
+    pub const NONE: Modifiers = Modifiers(0);
+    /// Left Control, `LCTRL()`.
+    pub const CTRL: Modifiers = Modifiers(0b0000_0001);
+    /// Left Alt, `LALT()`.
+    pub const LALT: Modifiers = Modifiers(0b0000_0010);
+    /// Right Alt (AltGr), `RALT()`.
+    pub const RALT: Modifiers = Modifiers(0b0000_0100);
+    /// Left Shift, `LSHIFT()`.
+    pub const SHIFT: Modifiers = Modifiers(0b0000_1000);
+    /// Left Gui, `LGUI()`.
+    pub const GUI: Modifiers = Modifiers(0b0001_0000);
+
+    const ALL: u8 = 0b0001_1111;
+    const WRAPPERS: [(Modifiers, &'static str); 5] = [
+        (Modifiers::CTRL, "LCTRL"),
+        (Modifiers::LALT, "LALT"),
+        (Modifiers::RALT, "RALT"),
+        (Modifiers::SHIFT, "LSHIFT"),
+        (Modifiers::GUI, "LGUI"),
+    ];
+
+    /// Create a set of modifiers from its bits, if they are all valid.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        (bits & !Self::ALL == 0).then(|| Modifiers(bits))
+    }
+
+    /// Return the bits of the modifier set.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Return whether every modifier in `other` is also in `self`.
+    pub fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Return whether the set is empty.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, rhs: Modifiers) -> Modifiers {
+        Modifiers(self.0 | rhs.0)
+    }
+}
+
+/// A decoded Kaleidoscope key.
+///
+/// Convert from the raw 16-bit representation with [`From`], and back with
+/// [`TryFrom`]. Convert from and to names with [`FromStr`] and [`Key::name`]
+/// (or [`fmt::Display`]).
+///
+/// Variants holding an index are only valid within the range Kaleidoscope
+/// reserves for them (e.g. 16 for [`Key::TapDance`]). Keys decoded from raw
+/// values are always within range; keys constructed by hand and out of range
+/// cannot be encoded.
+///
+/// ```
+/// use kaleidoscope_focus::key::{Key, Modifiers};
+///
+/// let key = Key::from(2052);
+/// assert_eq!(
+///     key,
+///     Key::Keyboard { code: 0x04, modifiers: Modifiers::SHIFT }
+/// );
+/// assert_eq!(key.name(), "LSHIFT(Key_A)");
+/// assert_eq!(u16::try_from("TD(2)".parse::<Key>()?)?, 53269);
+/// assert!(u16::try_from(Key::TapDance(16)).is_err());
+/// # Ok::<(), kaleidoscope_focus::Error>(())
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Key {
+    /// A key from the HID keyboard usage page, with optional modifiers.
+    ///
+    /// `Key_NoKey` is the keyboard key with code `0`, and no modifiers.
+    Keyboard {
+        /// The HID usage code of the key.
+        code: u8,
+        /// The modifiers held together with the key.
+        modifiers: Modifiers,
+    },
+    /// A key from the HID consumer control usage page (10 bits at most).
+    Consumer(u16),
+    /// A key from the HID system control usage page.
+    SystemControl(u8),
+    /// Toggle a layer, `LockLayer(n)`.
+    LockLayer(u8),
+    /// Switch to a layer while held, `ShiftToLayer(n)`.
+    ShiftToLayer(u8),
+    /// Move to a layer, `MoveToLayer(n)`.
+    MoveToLayer(u8),
+    /// A macro, `M(n)`.
+    Macro(u8),
+    /// Switch to the next LED effect, `Key_LEDEffectNext`.
+    LedEffectNext,
+    /// Switch to the previous LED effect, `Key_LEDEffectPrevious`.
+    LedEffectPrevious,
+    /// Toggle the LEDs on or off, `Key_LEDToggle`.
+    LedToggle,
+    /// A mouse key: movement, wheel, warp or button.
+    Mouse(u8),
+    /// A one-shot modifier, `OSM(mod)`. Holds the index of the modifier, with
+    /// `0` being `LeftControl`, and `7` being `RightGui`.
+    OneShotModifier(u8),
+    /// A one-shot layer, `OSL(n)`.
+    OneShotLayer(u8),
+    /// A dual-use modifier key, `MT(mod, key)`.
+    ModTap {
+        /// The index of the modifier, as in [`Key::OneShotModifier`].
+        modifier: u8,
+        /// The HID usage code of the key sent when tapped.
+        code: u8,
+    },
+    /// A dual-use layer key, `LT(layer, key)`.
+    LayerTap {
+        /// The layer to shift to when held.
+        layer: u8,
+        /// The HID usage code of the key sent when tapped.
+        code: u8,
+    },
+    /// A tap-dance key, `TD(n)`.
+    TapDance(u8),
+    /// A leader key, `LEAD(n)`.
+    Leader(u8),
+    /// The `Key_Cycle` key of the Cycle plugin.
+    Cycle,
+    /// The `SYSTER` key of the Syster plugin.
+    SysTer,
+    /// A TopsyTurvy key, `TOPSY(key)`, holding the HID usage code of the key.
+    TopsyTurvy(u8),
+    /// A steno key of the GeminiPR plugin.
+    Steno(u8),
+    /// Enable SpaceCadet, `Key_SpaceCadetEnable`.
+    SpaceCadetEnable,
+    /// Disable SpaceCadet, `Key_SpaceCadetDisable`.
+    SpaceCadetDisable,
+    /// The `Key_Redial` key of the Redial plugin.
+    Redial,
+    /// The `Key_Turbo` key of the Turbo plugin.
+    Turbo,
+    /// A dynamic macro, `DM(n)`.
+    DynamicMacro(u8),
+    /// The `OneShot_MetaStickyKey` of the OneShot plugin.
+    OneShotMetaSticky,
+    /// The `OneShot_ActiveStickyKey` of the OneShot plugin.
+    OneShotActiveSticky,
+    /// Cancel one-shot keys, `Key_OneShotCancel`.
+    OneShotCancel,
+    /// A CharShift key, `CS(n)`.
+    CharShift(u8),
+    /// A transparent key, `Key_Transparent`.
+    Transparent,
+    /// A value that does not correspond to any known key.
+    Raw(u16),
+}
+
+const MODIFIER_NAMES: [&str; 8] = [
+    "LeftControl",
+    "LeftShift",
+    "LeftAlt",
+    "LeftGui",
+    "RightControl",
+    "RightShift",
+    "RightAlt",
+    "RightGui",
+];
+
+/// Keys with a fixed name, that are not looked up from the tables below.
+const NAMED_KEYS: [Key; 15] = [
+    Key::LedEffectNext,
+    Key::LedEffectPrevious,
+    Key::LedToggle,
+    Key::Cycle,
+    Key::SysTer,
+    Key::SpaceCadetEnable,
+    Key::SpaceCadetDisable,
+    Key::Redial,
+    Key::Turbo,
+    Key::OneShotMetaSticky,
+    Key::OneShotActiveSticky,
+    Key::OneShotCancel,
+    Key::Transparent,
+    Key::ShiftToLayer(KEYMAP_NEXT),
+    Key::ShiftToLayer(KEYMAP_PREVIOUS),
+];
+
+impl Key {
+    /// The empty key, `Key_NoKey`.
+    pub const NO_KEY: Key = Key::Keyboard {
+        code: 0,
+        modifiers: Modifiers::NONE,
+    };
+
+    /// Return the canonical name of the key.
+    pub fn name(&self) -> String {
+        // Out of range modifiers are shown by index: they have no name.
+        let modifier_name = |i: u8| {
+            MODIFIER_NAMES
+                .get(i as usize)
+                .map_or_else(|| i.to_string(), |m| m.to_string())
+        };
+
+        match *self {
+            Key::Keyboard { code, modifiers } => {
+                let mut name = keyboard_name(code);
+                for (modifier, wrapper) in Modifiers::WRAPPERS.iter().rev() {
+                    if modifiers.contains(*modifier) {
+                        name = format!("{}({})", wrapper, name);
+                    }
+                }
+                name
+            }
+            Key::Consumer(usage) => match lookup_name(CONSUMER_NAMES, usage) {
+                Some(name) => name.to_string(),
+                None => format!("Consumer(0x{:03X})", usage),
+            },
+            Key::SystemControl(code) => match lookup_name(SYSTEM_CONTROL_NAMES, code) {
+                Some(name) => name.to_string(),
+                None => format!("SystemControl(0x{:02X})", code),
+            },
+            Key::LockLayer(n) => format!("LockLayer({})", n),
+            Key::ShiftToLayer(KEYMAP_NEXT) => "Key_KeymapNext_Momentary".to_string(),
+            Key::ShiftToLayer(KEYMAP_PREVIOUS) => "Key_KeymapPrevious_Momentary".to_string(),
+            Key::ShiftToLayer(n) => format!("ShiftToLayer({})", n),
+            Key::MoveToLayer(n) => format!("MoveToLayer({})", n),
+            Key::Macro(n) => format!("M({})", n),
+            Key::LedEffectNext => "Key_LEDEffectNext".to_string(),
+            Key::LedEffectPrevious => "Key_LEDEffectPrevious".to_string(),
+            Key::LedToggle => "Key_LEDToggle".to_string(),
+            Key::Mouse(code) => match lookup_name(MOUSE_NAMES, code) {
+                Some(name) => name.to_string(),
+                None => format!("MouseKey(0x{:02X})", code),
+            },
+            Key::OneShotModifier(i) => format!("OSM({})", modifier_name(i)),
+            Key::OneShotLayer(n) => format!("OSL({})", n),
+            Key::ModTap { modifier, code } => {
+                format!("MT({}, {})", modifier_name(modifier), tap_name(code))
+            }
+            Key::LayerTap { layer, code } => format!("LT({}, {})", layer, tap_name(code)),
+            Key::TapDance(n) => format!("TD({})", n),
+            Key::Leader(n) => format!("LEAD({})", n),
+            Key::Cycle => "Key_Cycle".to_string(),
+            Key::SysTer => "SYSTER".to_string(),
+            Key::TopsyTurvy(code) => format!("TOPSY({})", tap_name(code)),
+            Key::Steno(n) => format!("Steno({})", n),
+            Key::SpaceCadetEnable => "Key_SpaceCadetEnable".to_string(),
+            Key::SpaceCadetDisable => "Key_SpaceCadetDisable".to_string(),
+            Key::Redial => "Key_Redial".to_string(),
+            Key::Turbo => "Key_Turbo".to_string(),
+            Key::DynamicMacro(n) => format!("DM({})", n),
+            Key::OneShotMetaSticky => "OneShot_MetaStickyKey".to_string(),
+            Key::OneShotActiveSticky => "OneShot_ActiveStickyKey".to_string(),
+            Key::OneShotCancel => "Key_OneShotCancel".to_string(),
+            Key::CharShift(n) => format!("CS({})", n),
+            Key::Transparent => "Key_Transparent".to_string(),
+            Key::Raw(raw) => format!("Raw(0x{:04X})", raw),
+        }
+    }
+
+    fn from_range(raw: u16) -> Key {
+        use ranges::*;
+
+        let offset = |first: u16| (raw - first) as u8;
+        match raw {
+            OSM_FIRST..=OSM_LAST => Key::OneShotModifier(offset(OSM_FIRST)),
+            OSL_FIRST..=OSL_LAST => Key::OneShotLayer(offset(OSL_FIRST)),
+            DUM_FIRST..=DUM_LAST if raw != DUM_LAST => Key::ModTap {
+                modifier: ((raw - DUM_FIRST) >> 8) as u8,
+                code: offset(DUM_FIRST),
+            },
+            DUL_FIRST..=DUL_LAST if raw != DUL_LAST => Key::LayerTap {
+                layer: ((raw - DUL_FIRST) >> 8) as u8,
+                code: offset(DUL_FIRST),
+            },
+            TD_FIRST..=TD_LAST => Key::TapDance(offset(TD_FIRST)),
+            LEAD_FIRST..=LEAD_LAST => Key::Leader(offset(LEAD_FIRST)),
+            CYCLE => Key::Cycle,
+            SYSTER => Key::SysTer,
+            TT_FIRST..=TT_LAST => Key::TopsyTurvy(offset(TT_FIRST)),
+            STENO_FIRST..=STENO_LAST => Key::Steno(offset(STENO_FIRST)),
+            SC_FIRST => Key::SpaceCadetEnable,
+            SC_LAST => Key::SpaceCadetDisable,
+            REDIAL => Key::Redial,
+            TURBO => Key::Turbo,
+            DYNAMIC_MACRO_FIRST..=DYNAMIC_MACRO_LAST => {
+                Key::DynamicMacro(offset(DYNAMIC_MACRO_FIRST))
+            }
+            OS_META_STICKY => Key::OneShotMetaSticky,
+            OS_ACTIVE_STICKY => Key::OneShotActiveSticky,
+            OS_CANCEL => Key::OneShotCancel,
+            CS_FIRST..=CS_LAST => Key::CharShift(offset(CS_FIRST)),
+            0xFFFF => Key::Transparent,
+            _ => Key::Raw(raw),
+        }
+    }
+
+    fn from_synthetic(raw: u16) -> Key {
+        let flags = (raw >> 8) as u8 & !SYNTHETIC;
+        let code = raw as u8;
+
+        match flags {
+            f if f & !0b11 == IS_CONSUMER => Key::Consumer(raw & 0x3FF),
+            IS_SYSCTL => Key::SystemControl(code),
+            SWITCH_TO_KEYMAP => match code {
+                c if c < LAYER_SHIFT_OFFSET => Key::LockLayer(c),
+                c if c < LAYER_MOVE_OFFSET => Key::ShiftToLayer(c - LAYER_SHIFT_OFFSET),
+                c if c < LAYER_MOVE_OFFSET + LAYER_SHIFT_OFFSET => {
+                    Key::MoveToLayer(c - LAYER_MOVE_OFFSET)
+                }
+                _ => Key::Raw(raw),
+            },
+            f if f == IS_INTERNAL | LED_TOGGLE => match code {
+                0 => Key::LedEffectNext,
+                1 => Key::LedEffectPrevious,
+                2 => Key::LedToggle,
+                _ => Key::Raw(raw),
+            },
+            IS_MOUSE_KEY => Key::Mouse(code),
+            IS_MACRO => Key::Macro(code),
+            _ => Key::Raw(raw),
+        }
+    }
+}
+
+impl From<u16> for Key {
+    fn from(raw: u16) -> Self {
+        let flags = (raw >> 8) as u8;
+
+        if raw >= ranges::FIRST {
+            Key::from_range(raw)
+        } else if flags & RESERVED != 0 {
+            Key::Raw(raw)
+        } else if flags & SYNTHETIC != 0 {
+            Key::from_synthetic(raw)
+        } else {
+            match Modifiers::from_bits(flags) {
+                Some(modifiers) => Key::Keyboard {
+                    code: raw as u8,
+                    modifiers,
+                },
+                None => Key::Raw(raw),
+            }
+        }
+    }
+}
+
+impl TryFrom<Key> for u16 {
+    type Error = Error;
+
+    /// Encode a key into its raw 16-bit representation.
+    ///
+    /// Returns an [`Error::InvalidValue`] if the key holds an index or code
+    /// outside of the range Kaleidoscope reserves for it.
+    fn try_from(key: Key) -> Result<Self> {
+        use ranges::*;
+
+        let synthetic = |flags: u8, code: u8| u16::from_be_bytes([SYNTHETIC | flags, code]);
+        let out_of_range = || Error::InvalidValue(format!("`{}` is out of range", key));
+        let within = |n: u8, max: u8| (n <= max).then(|| n as u16).ok_or_else(out_of_range);
+        let layer = |n: u8| within(n, LAYER_SHIFT_OFFSET - 1).map(|n| n as u8);
+
+        Ok(match key {
+            Key::Keyboard { code, modifiers } => u16::from_be_bytes([modifiers.0, code]),
+            Key::Consumer(usage) if usage <= 0x3FF => synthetic(IS_CONSUMER, 0) | usage,
+            Key::Consumer(_) => return Err(out_of_range()),
+            Key::SystemControl(code) => synthetic(IS_SYSCTL, code),
+            Key::LockLayer(n) => synthetic(SWITCH_TO_KEYMAP, layer(n)?),
+            Key::ShiftToLayer(n) => synthetic(SWITCH_TO_KEYMAP, layer(n)? + LAYER_SHIFT_OFFSET),
+            Key::MoveToLayer(n) => synthetic(SWITCH_TO_KEYMAP, layer(n)? + LAYER_MOVE_OFFSET),
+            Key::Macro(n) => synthetic(IS_MACRO, n),
+            Key::LedEffectNext => synthetic(IS_INTERNAL | LED_TOGGLE, 0),
+            Key::LedEffectPrevious => synthetic(IS_INTERNAL | LED_TOGGLE, 1),
+            Key::LedToggle => synthetic(IS_INTERNAL | LED_TOGGLE, 2),
+            Key::Mouse(code) => synthetic(IS_MOUSE_KEY, code),
+            Key::OneShotModifier(i) => OSM_FIRST + within(i, 7)?,
+            Key::OneShotLayer(n) => OSL_FIRST + within(n, 7)?,
+            Key::ModTap { modifier, code } => DUM_FIRST + (within(modifier, 7)? << 8) + code as u16,
+            Key::LayerTap { layer, code } => DUL_FIRST + (within(layer, 7)? << 8) + code as u16,
+            Key::TapDance(n) => TD_FIRST + within(n, 15)?,
+            Key::Leader(n) => LEAD_FIRST + within(n, 7)?,
+            Key::Cycle => CYCLE,
+            Key::SysTer => SYSTER,
+            Key::TopsyTurvy(code) => TT_FIRST + code as u16,
+            Key::Steno(n) => STENO_FIRST + within(n, 42)?,
+            Key::SpaceCadetEnable => SC_FIRST,
+            Key::SpaceCadetDisable => SC_LAST,
+            Key::Redial => REDIAL,
+            Key::Turbo => TURBO,
+            Key::DynamicMacro(n) => DYNAMIC_MACRO_FIRST + within(n, 31)?,
+            Key::OneShotMetaSticky => OS_META_STICKY,
+            Key::OneShotActiveSticky => OS_ACTIVE_STICKY,
+            Key::OneShotCancel => OS_CANCEL,
+            Key::CharShift(n) => CS_FIRST + within(n, 63)?,
+            Key::Transparent => 0xFFFF,
+            Key::Raw(raw) => raw,
+        })
+    }
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Key::NO_KEY
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+impl FromStr for Key {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let invalid = || Error::InvalidValue(format!("`{}` is not a valid key name", s));
+
+        let (func, args) = match s.strip_suffix(')').and_then(|s| s.split_once('(')) {
+            Some((func, args)) => (func.trim(), args.trim()),
+            None => return parse_plain_name(s).ok_or_else(invalid),
+        };
+        let number = |max: u32| parse_number(args, max).ok_or_else(invalid);
+        let pair = || {
+            args.split_once(',')
+                .map(|(a, b)| (a.trim(), b.trim()))
+                .ok_or_else(invalid)
+        };
+
+        if let Some((modifier, _)) = Modifiers::WRAPPERS.iter().find(|(_, w)| *w == func) {
+            return match args.parse()? {
+                Key::Keyboard { code, modifiers } => Ok(Key::Keyboard {
+                    code,
+                    modifiers: modifiers | *modifier,
+                }),
+                _ => Err(invalid()),
+            };
+        }
+
+        let key = match func {
+            "Key" => Key::Keyboard {
+                code: number(0xFF)? as u8,
+                modifiers: Modifiers::NONE,
+            },
+            "Consumer" => Key::Consumer(number(0x3FF)? as u16),
+            "SystemControl" => Key::SystemControl(number(0xFF)? as u8),
+            "LockLayer" => Key::LockLayer(number(LAYER_SHIFT_OFFSET as u32 - 1)? as u8),
+            "ShiftToLayer" => Key::ShiftToLayer(number(LAYER_SHIFT_OFFSET as u32 - 1)? as u8),
+            "MoveToLayer" => Key::MoveToLayer(number(LAYER_SHIFT_OFFSET as u32 - 1)? as u8),
+            "M" => Key::Macro(number(0xFF)? as u8),
+            "MouseKey" => Key::Mouse(number(0xFF)? as u8),
+            "OSM" => Key::OneShotModifier(modifier_index(args).ok_or_else(invalid)?),
+            "OSL" => Key::OneShotLayer(number(7)? as u8),
+            "MT" => {
+                let (modifier, tap) = pair()?;
+                Key::ModTap {
+                    modifier: modifier_index(modifier).ok_or_else(invalid)?,
+                    code: parse_tap_name(tap).ok_or_else(invalid)?,
+                }
+            }
+            "LT" => {
+                let (layer, tap) = pair()?;
+                Key::LayerTap {
+                    layer: parse_number(layer, 7).ok_or_else(invalid)? as u8,
+                    code: parse_tap_name(tap).ok_or_else(invalid)?,
+                }
+            }
+            "TD" => Key::TapDance(number(15)? as u8),
+            "LEAD" => Key::Leader(number(7)? as u8),
+            "TOPSY" => Key::TopsyTurvy(parse_tap_name(args).ok_or_else(invalid)?),
+            "Steno" => Key::Steno(number(42)? as u8),
+            "DM" => Key::DynamicMacro(number(31)? as u8),
+            "CS" => Key::CharShift(number(63)? as u8),
+            "Raw" => Key::from(number(0xFFFF)? as u16),
+            _ => return Err(invalid()),
+        };
+        Ok(key)
+    }
+}
+
+fn parse_plain_name(name: &str) -> Option<Key> {
+    if let Some(key) = NAMED_KEYS.iter().find(|k| k.name() == name) {
+        return Some(*key);
+    }
+
+    lookup_code(KEYBOARD_NAMES, name)
+        .map(|code| Key::Keyboard {
+            code,
+            modifiers: Modifiers::NONE,
+        })
+        .or_else(|| lookup_code(CONSUMER_NAMES, name).map(Key::Consumer))
+        .or_else(|| lookup_code(SYSTEM_CONTROL_NAMES, name).map(Key::SystemControl))
+        .or_else(|| lookup_code(MOUSE_NAMES, name).map(Key::Mouse))
+}
+
+/// Parse a decimal, or `0x` prefixed hexadecimal number, no larger than `max`.
+fn parse_number(s: &str, max: u32) -> Option<u32> {
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    (n <= max).then(|| n)
+}
+
+fn modifier_index(name: &str) -> Option<u8> {
+    MODIFIER_NAMES
+        .iter()
+        .position(|m| *m == name)
+        .map(|i| i as u8)
+}
+
+fn keyboard_name(code: u8) -> String {
+    match lookup_name(KEYBOARD_NAMES, code) {
+        Some(name) => name.to_string(),
+        None => format!("Key(0x{:02X})", code),
+    }
+}
+
+/// The name of a key within `MT()`, `LT()` and `TOPSY()`: without the `Key_`
+/// prefix.
+fn tap_name(code: u8) -> String {
+    let name = keyboard_name(code);
+    match name.strip_prefix("Key_") {
+        Some(name) => name.to_string(),
+        None => name,
+    }
+}
+
+fn parse_tap_name(name: &str) -> Option<u8> {
+    match name.strip_prefix("Key(").and_then(|n| n.strip_suffix(')')) {
+        Some(n) => parse_number(n, 0xFF).map(|n| n as u8),
+        None => lookup_code(KEYBOARD_NAMES, &format!("Key_{}", name)),
+    }
+}
+
+fn lookup_name<T: PartialEq>(table: &[(T, &'static str)], code: T) -> Option<&'static str> {
+    table.iter().find(|(c, _)| *c == code).map(|(_, n)| *n)
+}
+
+fn lookup_code<T: Copy>(table: &[(T, &str)], name: &str) -> Option<T> {
+    table.iter().find(|(_, n)| *n == name).map(|(c, _)| *c)
+}
+
+const CONSUMER_NAMES: &[(u16, &str)] = &[
+    (0x30, "Consumer_Power"),
+    (0x32, "Consumer_Sleep"),
+    (0x40, "Consumer_Menu"),
+    (0x6F, "Consumer_DisplayBrightnessIncrement"),
+    (0x70, "Consumer_DisplayBrightnessDecrement"),
+    (0xB0, "Consumer_Play"),
+    (0xB1, "Consumer_Pause"),
+    (0xB2, "Consumer_Record"),
+    (0xB3, "Consumer_FastForward"),
+    (0xB4, "Consumer_Rewind"),
+    (0xB5, "Consumer_ScanNextTrack"),
+    (0xB6, "Consumer_ScanPreviousTrack"),
+    (0xB7, "Consumer_Stop"),
+    (0xB8, "Consumer_Eject"),
+    (0xCD, "Consumer_PlaySlashPause"),
+    (0xE2, "Consumer_Mute"),
+    (0xE9, "Consumer_VolumeIncrement"),
+    (0xEA, "Consumer_VolumeDecrement"),
+    (0x192, "Consumer_AL_Calculator"),
+    (0x221, "Consumer_AC_Search"),
+    (0x223, "Consumer_AC_Home"),
+    (0x224, "Consumer_AC_Back"),
+    (0x225, "Consumer_AC_Forward"),
+];
+
+const SYSTEM_CONTROL_NAMES: &[(u8, &str)] = &[
+    (0x81, "System_PowerDown"),
+    (0x82, "System_Sleep"),
+    (0x83, "System_WakeUp"),
+];
+
+const MOUSE_NAMES: &[(u8, &str)] = &[
+    (0x01, "Key_mouseUp"),
+    (0x02, "Key_mouseDn"),
+    (0x04, "Key_mouseL"),
+    (0x05, "Key_mouseUpL"),
+    (0x06, "Key_mouseDnL"),
+    (0x08, "Key_mouseR"),
+    (0x09, "Key_mouseUpR"),
+    (0x0A, "Key_mouseDnR"),
+    (0x11, "Key_mouseScrollUp"),
+    (0x12, "Key_mouseScrollDn"),
+    (0x14, "Key_mouseScrollL"),
+    (0x18, "Key_mouseScrollR"),
+    (0x21, "Key_mouseWarpEnd"),
+    (0x25, "Key_mouseWarpNW"),
+    (0x26, "Key_mouseWarpSW"),
+    (0x29, "Key_mouseWarpNE"),
+    (0x2A, "Key_mouseWarpSE"),
+    (0x41, "Key_mouseBtnL"),
+    (0x42, "Key_mouseBtnR"),
+    (0x44, "Key_mouseBtnM"),
+    (0x48, "Key_mouseBtnP"),
+    (0x50, "Key_mouseBtnN"),
+];
+
+const KEYBOARD_NAMES: &[(u8, &str)] = &[
+    (0x00, "Key_NoKey"),
+    (0x04, "Key_A"),
+    (0x05, "Key_B"),
+    (0x06, "Key_C"),
+    (0x07, "Key_D"),
+    (0x08, "Key_E"),
+    (0x09, "Key_F"),
+    (0x0A, "Key_G"),
+    (0x0B, "Key_H"),
+    (0x0C, "Key_I"),
+    (0x0D, "Key_J"),
+    (0x0E, "Key_K"),
+    (0x0F, "Key_L"),
+    (0x10, "Key_M"),
+    (0x11, "Key_N"),
+    (0x12, "Key_O"),
+    (0x13, "Key_P"),
+    (0x14, "Key_Q"),
+    (0x15, "Key_R"),
+    (0x16, "Key_S"),
+    (0x17, "Key_T"),
+    (0x18, "Key_U"),
+    (0x19, "Key_V"),
+    (0x1A, "Key_W"),
+    (0x1B, "Key_X"),
+    (0x1C, "Key_Y"),
+    (0x1D, "Key_Z"),
+    (0x1E, "Key_1"),
+    (0x1F, "Key_2"),
+    (0x20, "Key_3"),
+    (0x21, "Key_4"),
+    (0x22, "Key_5"),
+    (0x23, "Key_6"),
+    (0x24, "Key_7"),
+    (0x25, "Key_8"),
+    (0x26, "Key_9"),
+    (0x27, "Key_0"),
+    (0x28, "Key_Enter"),
+    (0x29, "Key_Escape"),
+    (0x2A, "Key_Backspace"),
+    (0x2B, "Key_Tab"),
+    (0x2C, "Key_Spacebar"),
+    (0x2D, "Key_Minus"),
+    (0x2E, "Key_Equals"),
+    (0x2F, "Key_LeftBracket"),
+    (0x30, "Key_RightBracket"),
+    (0x31, "Key_Backslash"),
+    (0x32, "Key_NonUsPound"),
+    (0x33, "Key_Semicolon"),
+    (0x34, "Key_Quote"),
+    (0x35, "Key_Backtick"),
+    (0x36, "Key_Comma"),
+    (0x37, "Key_Period"),
+    (0x38, "Key_Slash"),
+    (0x39, "Key_CapsLock"),
+    (0x3A, "Key_F1"),
+    (0x3B, "Key_F2"),
+    (0x3C, "Key_F3"),
+    (0x3D, "Key_F4"),
+    (0x3E, "Key_F5"),
+    (0x3F, "Key_F6"),
+    (0x40, "Key_F7"),
+    (0x41, "Key_F8"),
+    (0x42, "Key_F9"),
+    (0x43, "Key_F10"),
+    (0x44, "Key_F11"),
+    (0x45, "Key_F12"),
+    (0x46, "Key_PrintScreen"),
+    (0x47, "Key_ScrollLock"),
+    (0x48, "Key_Pause"),
+    (0x49, "Key_Insert"),
+    (0x4A, "Key_Home"),
+    (0x4B, "Key_PageUp"),
+    (0x4C, "Key_Delete"),
+    (0x4D, "Key_End"),
+    (0x4E, "Key_PageDown"),
+    (0x4F, "Key_RightArrow"),
+    (0x50, "Key_LeftArrow"),
+    (0x51, "Key_DownArrow"),
+    (0x52, "Key_UpArrow"),
+    (0x53, "Key_KeypadNumLock"),
+    (0x54, "Key_KeypadDivide"),
+    (0x55, "Key_KeypadMultiply"),
+    (0x56, "Key_KeypadSubtract"),
+    (0x57, "Key_KeypadAdd"),
+    (0x58, "Key_KeypadEnter"),
+    (0x59, "Key_Keypad1"),
+    (0x5A, "Key_Keypad2"),
+    (0x5B, "Key_Keypad3"),
+    (0x5C, "Key_Keypad4"),
+    (0x5D, "Key_Keypad5"),
+    (0x5E, "Key_Keypad6"),
+    (0x5F, "Key_Keypad7"),
+    (0x60, "Key_Keypad8"),
+    (0x61, "Key_Keypad9"),
+    (0x62, "Key_Keypad0"),
+    (0x63, "Key_KeypadDot"),
+    (0x64, "Key_NonUsBackslashAndPipe"),
+    (0x65, "Key_PcApplication"),
+    (0x66, "Key_Power"),
+    (0x67, "Key_KeypadEquals"),
+    (0x68, "Key_F13"),
+    (0x69, "Key_F14"),
+    (0x6A, "Key_F15"),
+    (0x6B, "Key_F16"),
+    (0x6C, "Key_F17"),
+    (0x6D, "Key_F18"),
+    (0x6E, "Key_F19"),
+    (0x6F, "Key_F20"),
+    (0x70, "Key_F21"),
+    (0x71, "Key_F22"),
+    (0x72, "Key_F23"),
+    (0x73, "Key_F24"),
+    (0x74, "Key_Execute"),
+    (0x75, "Key_Help"),
+    (0x76, "Key_Menu"),
+    (0x77, "Key_Select"),
+    (0x78, "Key_Stop"),
+    (0x79, "Key_Again"),
+    (0x7A, "Key_Undo"),
+    (0x7B, "Key_Cut"),
+    (0x7C, "Key_Copy"),
+    (0x7D, "Key_Paste"),
+    (0x7E, "Key_Find"),
+    (0x7F, "Key_Mute"),
+    (0x80, "Key_VolumeUp"),
+    (0x81, "Key_VolumeDown"),
+    (0x82, "Key_LockingCapsLock"),
+    (0x83, "Key_LockingNumLock"),
+    (0x84, "Key_LockingScrollLock"),
+    (0x85, "Key_KeypadComma"),
+    (0x86, "Key_KeypadEqualSign"),
+    (0x87, "Key_International1"),
+    (0x88, "Key_International2"),
+    (0x89, "Key_International3"),
+    (0x8A, "Key_International4"),
+    (0x8B, "Key_International5"),
+    (0x8C, "Key_International6"),
+    (0x8D, "Key_International7"),
+    (0x8E, "Key_International8"),
+    (0x8F, "Key_International9"),
+    (0x90, "Key_Lang1"),
+    (0x91, "Key_Lang2"),
+    (0x92, "Key_Lang3"),
+    (0x93, "Key_Lang4"),
+    (0x94, "Key_Lang5"),
+    (0x95, "Key_Lang6"),
+    (0x96, "Key_Lang7"),
+    (0x97, "Key_Lang8"),
+    (0x98, "Key_Lang9"),
+    (0x99, "Key_AlternateErase"),
+    (0x9A, "Key_Sysreq"),
+    (0x9B, "Key_Cancel"),
+    (0x9C, "Key_Clear"),
+    (0x9D, "Key_Prior"),
+    (0x9E, "Key_Return"),
+    (0x9F, "Key_Separator"),
+    (0xA0, "Key_Out"),
+    (0xA1, "Key_Oper"),
+    (0xA2, "Key_ClearSlashAgain"),
+    (0xA3, "Key_CrselSlashProps"),
+    (0xA4, "Key_Exsel"),
+    (0xB0, "Key_Keypad00"),
+    (0xB1, "Key_Keypad000"),
+    (0xB2, "Key_ThousandsSeparator"),
+    (0xB3, "Key_DecimalSeparator"),
+    (0xB4, "Key_CurrencyUnit"),
+    (0xB5, "Key_CurrencySubunit"),
+    (0xB6, "Key_KeypadLeftParen"),
+    (0xB7, "Key_KeypadRightParen"),
+    (0xB8, "Key_KeypadLeftCurlyBrace"),
+    (0xB9, "Key_KeypadRightCurlyBrace"),
+    (0xBA, "Key_KeypadTab"),
+    (0xBB, "Key_KeypadBackspace"),
+    (0xBC, "Key_KeypadA"),
+    (0xBD, "Key_KeypadB"),
+    (0xBE, "Key_KeypadC"),
+    (0xBF, "Key_KeypadD"),
+    (0xC0, "Key_KeypadE"),
+    (0xC1, "Key_KeypadF"),
+    (0xC2, "Key_KeypadXor"),
+    (0xC3, "Key_KeypadCarat"),
+    (0xC4, "Key_KeypadPercent"),
+    (0xC5, "Key_KeypadLessThan"),
+    (0xC6, "Key_KeypadGreaterThan"),
+    (0xC7, "Key_KeypadAmpersand"),
+    (0xC8, "Key_KeypadDoubleAmpersand"),
+    (0xC9, "Key_KeypadPipe"),
+    (0xCA, "Key_KeypadDoublePipe"),
+    (0xCB, "Key_KeypadColon"),
+    (0xCC, "Key_KeypadPoundSign"),
+    (0xCD, "Key_KeypadSpace"),
+    (0xCE, "Key_KeypadAtSign"),
+    (0xCF, "Key_KeypadExclamationPoint"),
+    (0xD0, "Key_KeypadMemoryStore"),
+    (0xD1, "Key_KeypadMemoryRecall"),
+    (0xD2, "Key_KeypadMemoryClear"),
+    (0xD3, "Key_KeypadMemoryAdd"),
+    (0xD4, "Key_KeypadMemorySubtract"),
+    (0xD5, "Key_KeypadMemoryMultiply"),
+    (0xD6, "Key_KeypadMemoryDivide"),
+    (0xD7, "Key_KeypadPlusSlashMinus"),
+    (0xD8, "Key_KeypadClear"),
+    (0xD9, "Key_KeypadClearEntry"),
+    (0xDA, "Key_KeypadBinary"),
+    (0xDB, "Key_KeypadOctal"),
+    (0xDC, "Key_KeypadDecimal"),
+    (0xDD, "Key_KeypadHexadecimal"),
+    (0xE0, "Key_LeftControl"),
+    (0xE1, "Key_LeftShift"),
+    (0xE2, "Key_LeftAlt"),
+    (0xE3, "Key_LeftGui"),
+    (0xE4, "Key_RightControl"),
+    (0xE5, "Key_RightShift"),
+    (0xE6, "Key_RightAlt"),
+    (0xE7, "Key_RightGui"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_code_round_trips() {
+        for code in 0..=u16::MAX {
+            let key = Key::from(code);
+            assert_eq!(u16::try_from(key).unwrap(), code);
+            assert_eq!(key.name().parse::<Key>().unwrap(), key, "{:#06x}", code);
+        }
+    }
+
+    #[test]
+    fn names_parse_with_whitespace_and_hex() {
+        assert_eq!(
+            " LCTRL( LSHIFT(Key_A) ) ".parse::<Key>().unwrap(),
+            Key::Keyboard {
+                code: 0x04,
+                modifiers: Modifiers::CTRL | Modifiers::SHIFT
+            }
+        );
+        assert_eq!("TD(0x0F)".parse::<Key>().unwrap(), Key::TapDance(15));
+        assert_eq!(
+            "MT(LeftShift, Escape)".parse::<Key>().unwrap(),
+            Key::ModTap {
+                modifier: 1,
+                code: 0x29
+            }
+        );
+        assert_eq!("Raw(0xFFFF)".parse::<Key>().unwrap(), Key::Transparent);
+    }
+
+    #[test]
+    fn out_of_range_keys_do_not_encode() {
+        let keys = [
+            Key::Consumer(0x400),
+            Key::Consumer(0xFFFF),
+            Key::LockLayer(42),
+            Key::ShiftToLayer(42),
+            Key::ShiftToLayer(250),
+            Key::MoveToLayer(42),
+            Key::OneShotModifier(8),
+            Key::OneShotLayer(8),
+            Key::ModTap {
+                modifier: 8,
+                code: 0,
+            },
+            Key::ModTap {
+                modifier: 200,
+                code: 0,
+            },
+            Key::LayerTap { layer: 8, code: 0 },
+            Key::TapDance(16),
+            Key::Leader(8),
+            Key::Steno(43),
+            Key::DynamicMacro(32),
+            Key::CharShift(64),
+        ];
+        for key in keys {
+            assert!(
+                matches!(u16::try_from(key), Err(Error::InvalidValue(_))),
+                "{:?}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn keys_at_the_end_of_their_range_round_trip() {
+        let keys = [
+            Key::Consumer(0x3FF),
+            Key::LockLayer(41),
+            Key::ShiftToLayer(41),
+            Key::MoveToLayer(41),
+            Key::OneShotModifier(7),
+            Key::OneShotLayer(7),
+            Key::ModTap {
+                modifier: 7,
+                code: 0xFF,
+            },
+            Key::LayerTap {
+                layer: 7,
+                code: 0xFF,
+            },
+            Key::TapDance(15),
+            Key::Leader(7),
+            Key::TopsyTurvy(0xFF),
+            Key::Steno(42),
+            Key::DynamicMacro(31),
+            Key::CharShift(63),
+        ];
+        for key in keys {
+            assert_eq!(Key::from(u16::try_from(key).unwrap()), key);
+        }
+    }
+
+    #[test]
+    fn out_of_range_keys_have_a_name() {
+        assert_eq!(Key::ShiftToLayer(250).name(), "ShiftToLayer(250)");
+        assert_eq!(Key::OneShotModifier(9).name(), "OSM(9)");
+        let key = Key::ModTap {
+            modifier: 200,
+            code: 0x04,
+        };
+        assert_eq!(key.name(), "MT(200, A)");
+        assert!(key.name().parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn invalid_names_do_not_parse() {
+        for name in [
+            "",
+            "()",
+            "TD(",
+            "TD()",
+            "TD(x)",
+            "Key_Nonexistent",
+            "TD(16)",
+            "OSL(-1)",
+            "MT(LeftControl)",
+            "LSHIFT(TD(1))",
+            "M(256)",
+            "Raw(0x10000)",
+        ] {
+            assert!(name.parse::<Key>().is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reading and writing the keymap of a keyboard.
+//!
+//! The keymap - as stored in `keymap.custom` - is a flat list of key codes,
+//! layer after layer, row after row. This module splits it up into [`Layer`]s
+//! of [`Key`]s, using the [`Geometry`] of the keyboard, and keeps track of
+//! changes, so that only as much as necessary is written back.
+//!
+//! # Examples
+//!
+//! ```
+//! # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+//! use kaleidoscope_focus::{key::Key, keymap::Geometry};
+//!
+//! # fn main() -> Result<(), kaleidoscope_focus::Error> {
+//! # let keyboard = MockKeyboard::new().with_command("keymap.custom", "1 2 3 4 5 6 7 8");
+//! # let mut conn = Focus::builder().interval(0).open_transport(keyboard.clone());
+//! let geometry = Geometry { rows: 2, cols: 2 };
+//! let mut keymap = conn.read_keymap(geometry)?;
+//! assert_eq!(keymap.layers.len(), 2);
+//! assert_eq!(keymap.get(1, 0, 1), Some(Key::from(6)));
+//!
+//! keymap.set(0, 1, 0, "Key_Tab".parse()?)?;
+//! conn.write_keymap(&mut keymap)?;
+//! # assert_eq!(keyboard.requests().last().unwrap().args, vec!["1", "2", "43"]);
+//! #   Ok(())
+//! # }
+//! ```
+
+use crate::key::Key;
+use crate::{protocol, Error, Focus, Result};
+
+/// The dimensions of a keyboard's key matrix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Geometry {
+    /// The number of rows.
+    pub rows: usize,
+    /// The number of columns.
+    pub cols: usize,
+}
+
+impl Geometry {
+    /// The geometry of the Keyboardio Model100.
+    pub const MODEL100: Geometry = Geometry { rows: 4, cols: 16 };
+    /// The geometry of the Keyboardio Atreus.
+    pub const ATREUS: Geometry = Geometry { rows: 4, cols: 12 };
+    /// The geometry of the Keyboardio Model01.
+    pub const MODEL01: Geometry = Geometry { rows: 4, cols: 16 };
+
+    /// Return the number of keys on a single layer.
+    pub fn keys(&self) -> usize {
+        self.rows * self.cols
+    }
+}
+
+/// A single layer of a [`Keymap`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layer {
+    geometry: Geometry,
+    keys: Vec<Key>,
+}
+
+impl Layer {
+    /// Create a new layer, with every key set to `Key_NoKey`.
+    pub fn new(geometry: Geometry) -> Self {
+        Self {
+            geometry,
+            keys: vec![Key::NO_KEY; geometry.keys()],
+        }
+    }
+
+    /// Return the geometry of the layer.
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    /// Return the key at the given position, if it is within bounds.
+    pub fn get(&self, row: usize, col: usize) -> Option<Key> {
+        self.index(row, col).map(|i| self.keys[i])
+    }
+
+    /// Set the key at the given position.
+    ///
+    /// Returns an [`Error::InvalidValue`] if the position is out of bounds.
+    pub fn set(&mut self, row: usize, col: usize, key: Key) -> Result<()> {
+        let i = self.index(row, col).ok_or_else(|| {
+            Error::InvalidValue(format!("Key position ({}, {}) is out of bounds", row, col))
+        })?;
+        self.keys[i] = key;
+        Ok(())
+    }
+
+    /// Return the keys of the layer, row after row.
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// Iterate over the rows of the layer.
+    ///
+    /// A layer without columns has no keys, and no rows either.
+    pub fn rows(&self) -> impl Iterator<Item = &[Key]> {
+        // `chunks` panics on zero, but there is nothing to chunk then anyway.
+        self.keys.chunks(self.geometry.cols.max(1))
+    }
+
+    fn index(&self, row: usize, col: usize) -> Option<usize> {
+        (row < self.geometry.rows && col < self.geometry.cols)
+            .then(|| row * self.geometry.cols + col)
+    }
+}
+
+/// The keymap of a keyboard, split into layers.
+///
+/// Keeps track of the keymap as it was last read from, or written to the
+/// keyboard, so that [`Focus::write_keymap`] can skip unchanged keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    /// The layers of the keymap.
+    pub layers: Vec<Layer>,
+    geometry: Geometry,
+    saved: Vec<Key>,
+}
+
+impl Keymap {
+    /// Create a new, empty keymap with the given geometry.
+    pub fn new(geometry: Geometry) -> Self {
+        Self {
+            layers: vec![],
+            geometry,
+            saved: vec![],
+        }
+    }
+
+    /// Parse a keymap from the reply to `keymap.custom`.
+    ///
+    /// The reply must contain a whole number of layers.
+    pub fn parse(reply: &str, geometry: Geometry) -> Result<Self> {
+        let keys: Vec<Key> = protocol::parse_numbers::<u16>("keymap.custom", reply)?
+            .into_iter()
+            .map(Key::from)
+            .collect();
+        if geometry.keys() == 0 || keys.len() % geometry.keys() != 0 {
+            return Err(Error::InvalidReply {
+                command: "keymap.custom".to_string(),
+                reason: format!(
+                    "{} keys do not fit into layers of {}x{}",
+                    keys.len(),
+                    geometry.rows,
+                    geometry.cols
+                ),
+            });
+        }
+
+        let layers = keys
+            .chunks(geometry.keys())
+            .map(|keys| Layer {
+                geometry,
+                keys: keys.to_vec(),
+            })
+            .collect();
+
+        Ok(Self {
+            layers,
+            geometry,
+            saved: keys,
+        })
+    }
+
+    /// Return the geometry of the keymap.
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    /// Return the key at the given position, if it is within bounds.
+    pub fn get(&self, layer: usize, row: usize, col: usize) -> Option<Key> {
+        self.layers.get(layer)?.get(row, col)
+    }
+
+    /// Set the key at the given position.
+    ///
+    /// Returns an [`Error::InvalidValue`] if the position is out of bounds.
+    pub fn set(&mut self, layer: usize, row: usize, col: usize, key: Key) -> Result<()> {
+        self.layers
+            .get_mut(layer)
+            .ok_or_else(|| Error::InvalidValue(format!("Layer {} does not exist", layer)))?
+            .set(row, col, key)
+    }
+
+    /// Return the keys of every layer, as a flat list.
+    pub fn keys(&self) -> Vec<Key> {
+        self.layers
+            .iter()
+            .flat_map(|l| l.keys.iter().copied())
+            .collect()
+    }
+
+    /// Return whether the keymap changed since it was last read or written.
+    pub fn is_changed(&self) -> bool {
+        self.keys() != self.saved
+    }
+
+    /// Return the keys that need to be written to bring the keyboard up to date.
+    ///
+    /// Since `keymap.custom` can only be written from the start, this is every
+    /// key up to, and including the last changed one.
+    fn pending(&self) -> Vec<Key> {
+        let mut keys = self.keys();
+        let len = keys
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(i, k)| self.saved.get(*i) != Some(k))
+            .map_or(0, |(i, _)| i + 1);
+        keys.truncate(len);
+        keys
+    }
+}
+
+impl Focus {
+    /// Read the custom keymap from the keyboard.
+    ///
+    /// See the [module documentation](crate::keymap) for an example.
+    pub fn read_keymap(&mut self, geometry: Geometry) -> Result<Keymap> {
+        let reply = self.command("keymap.custom")?;
+        if reply.is_empty() {
+            return Err(Error::UnknownCommand("keymap.custom".to_string()));
+        }
+        Keymap::parse(&reply, geometry)
+    }
+
+    /// Write the changes made to a keymap back to the keyboard.
+    ///
+    /// Only writes as much of the keymap as needed: nothing at all if it did
+    /// not change, and only up to the last changed key otherwise.
+    ///
+    /// See the [module documentation](crate::keymap) for an example.
+    pub fn write_keymap(&mut self, keymap: &mut Keymap) -> Result<()> {
+        let pending = keymap.pending();
+        if !pending.is_empty() {
+            let codes = pending
+                .into_iter()
+                .map(u16::try_from)
+                .collect::<Result<Vec<_>>>()?;
+            let args = protocol::format_numbers(codes);
+            self.request("keymap.custom", Some(&args))?;
+        }
+        keymap.saved = keymap.keys();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GEOMETRY: Geometry = Geometry { rows: 2, cols: 3 };
+
+    #[test]
+    fn keymap_is_split_into_layers_and_rows() {
+        let keymap = Keymap::parse("1 2 3 4 5 6 7 8 9 10 11 12", GEOMETRY).unwrap();
+        assert_eq!(keymap.layers.len(), 2);
+        assert_eq!(keymap.get(1, 1, 0), Some(Key::from(10)));
+        assert_eq!(keymap.get(1, 2, 0), None);
+        assert_eq!(keymap.get(2, 0, 0), None);
+
+        let rows: Vec<&[Key]> = keymap.layers[0].rows().collect();
+        assert_eq!(
+            rows,
+            [&keymap.layers[0].keys()[..3], &keymap.layers[0].keys()[3..]]
+        );
+        assert_eq!(
+            keymap
+                .keys()
+                .into_iter()
+                .map(|k| u16::try_from(k).unwrap())
+                .collect::<Vec<_>>(),
+            (1..=12).collect::<Vec<_>>()
+        );
+        assert!(!keymap.is_changed());
+    }
+
+    #[test]
+    fn malformed_keymaps_do_not_parse() {
+        assert_eq!(Keymap::parse("", GEOMETRY).unwrap().layers.len(), 0);
+
+        for reply in [
+            "1 2 3 4 5",
+            "1 2 3 4 5 x",
+            "1 2 3 4 5 65536",
+            "1 2 3 4 5 -1",
+        ] {
+            assert!(
+                matches!(
+                    Keymap::parse(reply, GEOMETRY),
+                    Err(Error::InvalidReply { .. })
+                ),
+                "{}",
+                reply
+            );
+        }
+        assert!(Keymap::parse("", Geometry { rows: 0, cols: 0 }).is_err());
+    }
+
+    #[test]
+    fn only_keys_up_to_the_last_change_are_pending() {
+        let mut keymap = Keymap::parse(&"0 ".repeat(12), GEOMETRY).unwrap();
+        assert!(keymap.pending().is_empty());
+
+        keymap.set(0, 1, 0, Key::from(4)).unwrap();
+        assert!(keymap.is_changed());
+        assert_eq!(keymap.pending().len(), 4);
+
+        assert!(keymap.set(0, 2, 0, Key::from(4)).is_err());
+        assert!(keymap.set(2, 0, 0, Key::from(4)).is_err());
+    }
+
+    #[test]
+    fn layer_without_columns_has_no_rows() {
+        for geometry in [Geometry { rows: 0, cols: 0 }, Geometry { rows: 4, cols: 0 }] {
+            let layer = Layer::new(geometry);
+            assert_eq!(layer.rows().count(), 0);
+            assert_eq!(layer.get(0, 0), None);
+        }
+    }
+}
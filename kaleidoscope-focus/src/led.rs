@@ -0,0 +1,321 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reading and writing the LED palette and colormap of a keyboard.
+//!
+//! The `palette` command holds 16 colors, and `colormap.map` holds - for every
+//! layer, and every LED on it - an index into the palette.
+//!
+//! # Examples
+//!
+//! ```
+//! # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+//! use kaleidoscope_focus::led::Rgb;
+//!
+//! # fn main() -> Result<(), kaleidoscope_focus::Error> {
+//! # let keyboard = MockKeyboard::new()
+//! #     .with_command("palette", &"0 0 0 ".repeat(16))
+//! #     .with_command("colormap.map", "0 1 2 3 4 5");
+//! # let mut conn = Focus::builder().interval(0).open_transport(keyboard);
+//! let mut palette = conn.read_palette()?;
+//! palette.0[1] = "#ff0000".parse()?;
+//! conn.write_palette(&palette)?;
+//!
+//! let mut colormap = conn.read_colormap(3)?;
+//! assert_eq!(colormap.layers, vec![vec![0, 1, 2], vec![3, 4, 5]]);
+//! colormap.layers[0][0] = 1;
+//! conn.write_colormap(&colormap, 3)?;
+//! #   Ok(())
+//! # }
+//! ```
+
+use crate::{protocol, Error, Focus, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// The number of colors in a [`Palette`].
+pub const PALETTE_SIZE: usize = 16;
+
+/// A color, with red, green and blue components.
+///
+/// Displayed - and parsed - in the `#rrggbb` hexadecimal form.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Rgb {
+    /// The red component.
+    pub r: u8,
+    /// The green component.
+    pub g: u8,
+    /// The blue component.
+    pub b: u8,
+}
+
+impl Rgb {
+    /// Create a new color.
+    pub fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+impl FromStr for Rgb {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidValue(format!("`{}` is not a valid #rrggbb color", s));
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+
+        Ok(Self::new(component(0)?, component(2)?, component(4)?))
+    }
+}
+
+/// The LED palette of a keyboard.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Palette(pub [Rgb; PALETTE_SIZE]);
+
+impl Palette {
+    /// Parse a palette from the reply to `palette`.
+    pub fn parse(reply: &str) -> Result<Self> {
+        let values: Vec<u8> = protocol::parse_numbers("palette", reply)?;
+        if values.len() != PALETTE_SIZE * 3 {
+            return Err(Error::InvalidReply {
+                command: "palette".to_string(),
+                reason: format!("expected {} values, got {}", PALETTE_SIZE * 3, values.len()),
+            });
+        }
+
+        let mut palette = Palette::default();
+        for (color, rgb) in palette.0.iter_mut().zip(values.chunks(3)) {
+            *color = Rgb::new(rgb[0], rgb[1], rgb[2]);
+        }
+        Ok(palette)
+    }
+
+    fn to_args(self) -> Vec<String> {
+        protocol::format_numbers(self.0.iter().flat_map(|c| [c.r, c.g, c.b]))
+    }
+}
+
+/// The colormap of a keyboard: palette indexes for every LED, on every layer.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Colormap {
+    /// The layers of the colormap, each holding a palette index per LED.
+    pub layers: Vec<Vec<u8>>,
+}
+
+impl Colormap {
+    /// Parse a colormap from the reply to `colormap.map`.
+    ///
+    /// The reply must contain a whole number of layers, of `leds` each.
+    pub fn parse(reply: &str, leds: usize) -> Result<Self> {
+        let indexes: Vec<u8> = protocol::parse_numbers("colormap.map", reply)?;
+        if leds == 0 || indexes.len() % leds != 0 {
+            return Err(Error::InvalidReply {
+                command: "colormap.map".to_string(),
+                reason: format!(
+                    "{} values do not fit into layers of {} LEDs",
+                    indexes.len(),
+                    leds
+                ),
+            });
+        }
+
+        Ok(Self {
+            layers: indexes.chunks(leds).map(|l| l.to_vec()).collect(),
+        })
+    }
+
+    /// Check that the colormap can be written to a keyboard.
+    ///
+    /// Every layer must have exactly `leds` LEDs, there must be no more than
+    /// `max_layers` layers, and every index must be within the palette.
+    pub fn validate(&self, leds: usize, max_layers: usize) -> Result<()> {
+        if self.layers.len() > max_layers {
+            return Err(Error::InvalidValue(format!(
+                "The colormap has {} layers, but the keyboard only has room for {}",
+                self.layers.len(),
+                max_layers
+            )));
+        }
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            if layer.len() != leds {
+                return Err(Error::InvalidValue(format!(
+                    "Layer {} has {} LEDs, expected {}",
+                    i,
+                    layer.len(),
+                    leds
+                )));
+            }
+            if let Some(index) = layer.iter().find(|&&index| index as usize >= PALETTE_SIZE) {
+                return Err(Error::InvalidValue(format!(
+                    "Palette index {} on layer {} is out of range",
+                    index, i
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Focus {
+    /// Read the LED palette from the keyboard.
+    ///
+    /// See the [module documentation](crate::led) for an example.
+    pub fn read_palette(&mut self) -> Result<Palette> {
+        let reply = self.command("palette")?;
+        if reply.is_empty() {
+            return Err(Error::UnknownCommand("palette".to_string()));
+        }
+        Palette::parse(&reply)
+    }
+
+    /// Write the LED palette to the keyboard.
+    ///
+    /// See the [module documentation](crate::led) for an example.
+    pub fn write_palette(&mut self, palette: &Palette) -> Result<()> {
+        self.request("palette", Some(&palette.to_args()))?;
+        Ok(())
+    }
+
+    /// Read the colormap from the keyboard, with `leds` LEDs per layer.
+    ///
+    /// See the [module documentation](crate::led) for an example.
+    pub fn read_colormap(&mut self, leds: usize) -> Result<Colormap> {
+        let reply = self.command("colormap.map")?;
+        if reply.is_empty() {
+            return Err(Error::UnknownCommand("colormap.map".to_string()));
+        }
+        Colormap::parse(&reply, leds)
+    }
+
+    /// Write the colormap to a keyboard with `leds` LEDs per layer.
+    ///
+    /// Validates the colormap before writing, against the number of LEDs, and
+    /// the number of layers the keyboard has room for - as many as fit into
+    /// its current colormap. See [`Colormap::validate`].
+    ///
+    /// See the [module documentation](crate::led) for an example.
+    pub fn write_colormap(&mut self, colormap: &Colormap, leds: usize) -> Result<()> {
+        let current = self.read_colormap(leds)?;
+        colormap.validate(leds, current.layers.len())?;
+
+        let args = protocol::format_numbers(colormap.layers.iter().flatten());
+        self.request("colormap.map", Some(&args))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockKeyboard;
+
+    #[test]
+    fn colors_round_trip() {
+        for color in [Rgb::new(0, 0, 0), Rgb::new(0x12, 0xab, 0xff)] {
+            assert_eq!(color.to_string().parse::<Rgb>().unwrap(), color);
+        }
+        assert_eq!("12ABff".parse::<Rgb>().unwrap(), Rgb::new(0x12, 0xab, 0xff));
+    }
+
+    #[test]
+    fn malformed_colors_do_not_parse() {
+        for color in ["", "#", "#12345", "#1234567", "#12345g", "#+1+2+3", "#ééé"] {
+            assert!(color.parse::<Rgb>().is_err(), "{}", color);
+        }
+    }
+
+    #[test]
+    fn palette_round_trips() {
+        let reply: Vec<String> = (0..PALETTE_SIZE * 3).map(|i| i.to_string()).collect();
+        let palette = Palette::parse(&reply.join(" ")).unwrap();
+        assert_eq!(palette.0[1], Rgb::new(3, 4, 5));
+        assert_eq!(palette.to_args(), reply);
+    }
+
+    #[test]
+    fn palette_must_be_complete() {
+        assert!(Palette::parse("").is_err());
+        assert!(Palette::parse(&"0 ".repeat(PALETTE_SIZE * 3 - 1)).is_err());
+        assert!(Palette::parse(&"0 ".repeat(PALETTE_SIZE * 3 + 1)).is_err());
+        assert!(Palette::parse(&"256 ".repeat(PALETTE_SIZE * 3)).is_err());
+    }
+
+    #[test]
+    fn colormap_is_split_into_layers() {
+        let colormap = Colormap::parse("0 1 2 3 4 5", 3).unwrap();
+        assert_eq!(colormap.layers, vec![vec![0, 1, 2], vec![3, 4, 5]]);
+        assert_eq!(Colormap::parse("", 3).unwrap().layers.len(), 0);
+
+        assert!(Colormap::parse("0 1 2 3", 3).is_err());
+        assert!(Colormap::parse("0 1 2", 0).is_err());
+        assert!(Colormap::parse("0 x 2", 3).is_err());
+    }
+
+    #[test]
+    fn colormap_validation() {
+        let colormap = |layers: Vec<Vec<u8>>| Colormap { layers };
+        assert!(colormap(vec![vec![0, 15], vec![1, 2]])
+            .validate(2, 2)
+            .is_ok());
+        assert!(colormap(vec![]).validate(2, 0).is_ok());
+
+        assert!(colormap(vec![vec![0], vec![1]]).validate(1, 1).is_err());
+        assert!(colormap(vec![vec![0, 1], vec![1]]).validate(2, 2).is_err());
+        assert!(colormap(vec![vec![0, 1], vec![1, 2]])
+            .validate(1, 4)
+            .is_err());
+        assert!(colormap(vec![vec![0, 16]]).validate(2, 1).is_err());
+    }
+
+    #[test]
+    fn colormaps_are_checked_against_the_keyboard() {
+        let keyboard = MockKeyboard::new().with_command("colormap.map", &"0 ".repeat(8));
+        let mut conn = Focus::builder()
+            .interval(0)
+            .open_transport(keyboard.clone());
+
+        // Three layers of two LEDs fit into the eight stored values, but the
+        // keyboard has four LEDs per layer.
+        let narrow = Colormap {
+            layers: vec![vec![1; 2]; 3],
+        };
+        assert!(matches!(
+            conn.write_colormap(&narrow, 4),
+            Err(Error::InvalidValue(_))
+        ));
+        let tall = Colormap {
+            layers: vec![vec![1; 4]; 3],
+        };
+        assert!(conn.write_colormap(&tall, 4).is_err());
+        // Neither was written.
+        assert!(keyboard.requests().iter().all(|r| r.args.is_empty()));
+
+        let fitting = Colormap {
+            layers: vec![vec![1; 4]; 2],
+        };
+        conn.write_colormap(&fitting, 4).unwrap();
+        assert_eq!(keyboard.get("colormap.map").unwrap(), "1 1 1 1 1 1 1 1");
+    }
+}
@@ -20,7 +20,8 @@
 //!
 //! This library is a very thin layer on top of `serialport`, implementing a
 //! handful of convenience functions to make it easy to communicate with devices
-//! speaking Kaleidoscope's [`Focus`] protocol.
+//! speaking Kaleidoscope's [`Focus`] protocol. Other transports can be plugged
+//! in by implementing [`FocusTransport`].
 //!
 //! Start at [`struct.Focus`] to discover what the crate provides.
 //!
@@ -28,19 +29,60 @@
 //! [`Kaleidoscope`]: https://github.com/keyboardio/Kaleidoscope
 //! [`Focus`]: https://kaleidoscope.readthedocs.io/en/latest/plugins/Kaleidoscope-FocusSerial.html
 
-use serialport::SerialPort;
-use std::io::{self, Write};
+use std::io;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use trace::{Direction, TraceEvent};
+
+mod capabilities;
+pub use capabilities::Capabilities;
+
+mod error;
+pub use error::{Error, Result};
+
+mod reconnect;
+use reconnect::Reconnect;
+pub use reconnect::ReconnectPolicy;
+
+mod protocol;
+use protocol::ReplyReader;
+
+mod transport;
+pub use transport::FocusTransport;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncFocus;
+
+pub mod devices;
+pub mod key;
+pub mod keymap;
+pub mod led;
+pub mod macros;
+pub mod tapdance;
+pub mod trace;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+type TraceHook = Box<dyn Fn(&TraceEvent) + 'static>;
 
 /// The representation of a connection to a keyboard, used for all communication.
 ///
-/// Constructed using a builder pattern, using [`Focus::create`].
+/// Constructed using a builder pattern, using [`Focus::create`], or
+/// [`Focus::builder`] when using a custom [`FocusTransport`].
 pub struct Focus {
-    port: Box<dyn SerialPort>,
+    transport: Box<dyn FocusTransport>,
     chunk_size: usize,
     interval: u64,
+    timeout: u64,
     progress_report: Box<dyn Fn(usize) + 'static>,
+    trace: Option<TraceHook>,
+    opened: Instant,
+    control_signals: bool,
+    capabilities: Option<Capabilities>,
+    reconnect: Option<Reconnect>,
 }
 
 impl Focus {
@@ -53,19 +95,37 @@ impl Focus {
     ///
     /// ```no_run
     /// # use kaleidoscope_focus::Focus;
-    /// # fn main() -> Result<(), std::io::Error> {
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
     /// let mut conn = Focus::create("/dev/ttyACM0")
     ///     .chunk_size(32)
     ///     .interval(50)
+    ///     .timeout(5000)
     ///     .open()?;
     /// #   Ok(())
     /// # }
     /// ```
-    pub fn create(device: &str) -> FocusBuilder {
+    pub fn create(device: &str) -> FocusBuilder<'_> {
         FocusBuilder {
-            device,
+            device: Some(device),
             chunk_size: 32,
             interval: 50,
+            timeout: 10000,
+            reconnect: None,
+        }
+    }
+
+    /// Create a new connection without a device, using a Builder pattern.
+    ///
+    /// Meant to be used with [`FocusBuilder::open_transport`], to talk to the
+    /// keyboard over a custom [`FocusTransport`]. See the documentation of the
+    /// trait for an example.
+    pub fn builder() -> FocusBuilder<'static> {
+        FocusBuilder {
+            device: None,
+            chunk_size: 32,
+            interval: 50,
+            timeout: 10000,
+            reconnect: None,
         }
     }
 
@@ -75,111 +135,127 @@ impl Focus {
     /// the reply to the request.
     ///
     /// May return an empty string if the command is unknown, or if it does not
-    /// have any output.
+    /// have any output. Use [`Capabilities::require`] on the
+    /// [capabilities](Focus::capabilities) of the keyboard to tell the two
+    /// apart.
     ///
     /// # Examples
     ///
-    /// ```no_run
-    /// # use kaleidoscope_focus::Focus;
-    /// # fn main() -> Result<(), std::io::Error> {
-    /// let mut conn = Focus::create("/dev/ttyACM0").open()?;
+    /// ```
+    /// # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+    /// # let keyboard = MockKeyboard::new();
+    /// # let mut conn = Focus::builder().interval(0).open_transport(keyboard);
     /// let reply = conn.request("help", None);
     /// assert!(reply.is_ok());
     /// #   Ok(())
     /// # }
     /// ```
     ///
-    /// ```no_run
-    /// # use kaleidoscope_focus::Focus;
+    /// ```
+    /// # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
     /// # use indicatif::ProgressBar;
-    /// # fn main() -> Result<(), std::io::Error> {
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+    /// # let keyboard = MockKeyboard::new().with_command("settings.version", "1");
+    /// # let mut conn = Focus::builder().interval(0).open_transport(keyboard);
     /// let progress = ProgressBar::new(0);
-    /// let mut conn = Focus::create("/dev/ttyACM0").open()?;
     /// conn.set_progress_report(move |delta| {
     ///   progress.inc(delta.try_into().unwrap());
     /// });
     /// let reply = conn.request("settings.version", None)?;
-    /// assert_eq!(reply, "1 ");
+    /// assert_eq!(reply, "1");
     /// #   Ok(())
     /// # }
     /// ```
-    pub fn request(
-        &mut self,
-        command: &str,
-        args: Option<&[String]>,
-    ) -> Result<String, std::io::Error> {
-        self.send(command, args)?.receive()
+    ///
+    /// If a [`ReconnectPolicy`] is set, and the keyboard goes away, waits for it
+    /// to come back, and retries the request if it is idempotent.
+    pub fn request(&mut self, command: &str, args: Option<&[String]>) -> Result<String> {
+        let mut retries = 0;
+        loop {
+            match self.send(command, args).and_then(|conn| conn.receive()) {
+                Err(Error::Disconnected(e)) if self.reconnect.is_some() => {
+                    if !self.reconnect() {
+                        return Err(Error::Disconnected(e));
+                    }
+                    let policy = &self.reconnect.as_ref().unwrap().policy;
+                    if retries >= policy.retries || !(policy.idempotent)(command, args) {
+                        return Err(Error::Disconnected(e));
+                    }
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
     }
 
-    fn send(
-        &mut self,
-        command: &str,
-        args: Option<&[String]>,
-    ) -> Result<&mut Self, std::io::Error> {
-        let request = format!("{} {}\n", command, args.unwrap_or_default().join(" "));
-        self.port.write_data_terminal_ready(true)?;
+    fn send(&mut self, command: &str, args: Option<&[String]>) -> Result<&mut Self> {
+        let request = protocol::format_request(command, args);
+        if self.control_signals {
+            self.transport.write_data_terminal_ready(true)?;
+        }
 
         if self.chunk_size > 0 {
             for c in request.as_bytes().chunks(self.chunk_size) {
-                self.port.write_all(c)?;
+                self.transport.write_all(c)?;
+                self.trace(Direction::Sent, c);
                 thread::sleep(Duration::from_millis(self.interval));
                 (self.progress_report)(c.len());
             }
         } else {
-            self.port.write_all(request.as_bytes())?;
+            self.transport.write_all(request.as_bytes())?;
+            self.trace(Direction::Sent, request.as_bytes());
             (self.progress_report)(request.len());
         }
 
         Ok(self)
     }
 
-    fn receive(&mut self) -> Result<String, std::io::Error> {
+    fn receive(&mut self) -> Result<String> {
+        let deadline = Instant::now() + Duration::from_millis(self.timeout);
         let mut buffer = [0; 1024];
-        let mut reply = vec![];
-
-        self.port.read_data_set_ready()?;
-        self.wait_for_data()?;
+        let mut reader = ReplyReader::default();
 
         loop {
-            match self.port.read(buffer.as_mut_slice()) {
-                // EOF
-                Ok(0) => break,
+            match self.transport.read(buffer.as_mut_slice()) {
+                // End of file: the other end went away.
+                Ok(0) => return Err(Error::Disconnected(io::ErrorKind::UnexpectedEof.into())),
                 Ok(t) => {
-                    reply.extend(&buffer[..t]);
+                    self.trace(Direction::Received, &buffer[..t]);
                     (self.progress_report)(t);
+                    if let Some(reply) = reader.feed(&buffer[..t]) {
+                        return reply;
+                    }
+                    continue;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                    break;
-                }
+                Err(ref e) if no_data(e) => {}
                 Err(e) => {
-                    return Err(e);
+                    return Err(e.into());
                 }
             }
 
-            thread::sleep(Duration::from_millis(self.interval));
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            thread::sleep(Duration::from_millis(1));
         }
-
-        Ok(String::from_utf8_lossy(&reply)
-            .lines()
-            .filter(|l| !l.is_empty() && *l != ".")
-            .collect::<Vec<&str>>()
-            .join("\n"))
     }
 
     /// Send a command - a request without arguments - to the keyboard.
     ///
     /// See [`Focus::request`], this is the same, but without any arguments.
     ///
-    /// ```no_run
-    /// # use kaleidoscope_focus::Focus;
-    /// # fn main() -> Result<(), std::io::Error> {
-    /// let mut conn = Focus::create("/dev/ttyACM0").open()?;
+    /// ```
+    /// # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+    /// # let keyboard = MockKeyboard::new().with_command("settings.version", "1");
+    /// # let mut conn = Focus::builder().interval(0).open_transport(keyboard);
     /// let reply = conn.command("settings.version")?;
-    /// assert_eq!(reply, "1 ");
+    /// assert_eq!(reply, "1");
     /// #   Ok(())
     /// # }
     /// ```
-    pub fn command(&mut self, command: &str) -> Result<String, std::io::Error> {
+    pub fn command(&mut self, command: &str) -> Result<String> {
         self.request(command, None)
     }
 
@@ -189,12 +265,13 @@ impl Focus {
     /// be used to display progress bars and the like. The reporter function
     /// takes a single `usize` argument, and returns nothing.
     ///
-    /// ```no_run
-    /// # use kaleidoscope_focus::Focus;
+    /// ```
+    /// # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
     /// # use indicatif::ProgressBar;
-    /// # fn main() -> Result<(), std::io::Error> {
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+    /// # let keyboard = MockKeyboard::new().with_command("version", "0.1.0");
+    /// # let mut conn = Focus::builder().interval(0).open_transport(keyboard);
     /// let progress = ProgressBar::new(0);
-    /// let mut conn = Focus::create("/dev/ttyACM0").open()?;
     /// conn.set_progress_report(move |delta| {
     ///   progress.inc(delta.try_into().unwrap());
     /// });
@@ -207,16 +284,35 @@ impl Focus {
         self.progress_report = Box::new(progress_report);
     }
 
-    /// Flush any pending data.
+    /// Set the trace hook, called for every chunk of data sent or received.
     ///
-    /// Sends an empty command, and then waits until the keyboard stops sending
-    /// data. The intended use is to clear any pending I/O operations in flight.
+    /// Tracing is off by default. See the [`trace`] module for details, and an
+    /// example.
+    pub fn set_trace(&mut self, trace: impl Fn(&TraceEvent) + 'static) {
+        self.trace = Some(Box::new(trace));
+    }
+
+    fn trace(&self, direction: Direction, data: &[u8]) {
+        if let Some(trace) = &self.trace {
+            trace(&TraceEvent {
+                elapsed: self.opened.elapsed(),
+                direction,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    /// Flush any pending data.
     ///
-    /// ```no_run
-    /// # use kaleidoscope_focus::Focus;
-    /// # fn main() -> Result<(), std::io::Error> {
-    /// let mut conn = Focus::create("/dev/ttyACM0").open()?;
+    /// Sends an empty command, waits for its reply, and discards anything else
+    /// the keyboard may have sent. The intended use is to clear any pending I/O
+    /// operations in flight.
     ///
+    /// ```
+    /// # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+    /// # let keyboard = MockKeyboard::new().with_command("settings.version", "1");
+    /// # let mut conn = Focus::builder().interval(0).open_transport(keyboard);
     /// /// Send a request whose output we're not interested in.
     /// conn.command("help")?;
     /// /// Flush it!
@@ -224,96 +320,120 @@ impl Focus {
     ///
     /// /// ...and then send the request we want the output of.
     /// let reply = conn.command("settings.version")?;
-    /// assert_eq!(reply, "1 ");
+    /// assert_eq!(reply, "1");
     /// #   Ok(())
     /// # }
     /// ```
-    pub fn flush(&mut self) -> Result<&mut Self, std::io::Error> {
+    pub fn flush(&mut self) -> Result<&mut Self> {
         self.command(" ")?;
+
+        let mut buffer = [0; 1024];
+        while self.transport.bytes_available()? > 0 {
+            match self.transport.read(buffer.as_mut_slice()) {
+                Ok(0) => break,
+                Ok(t) => self.trace(Direction::Received, &buffer[..t]),
+                Err(ref e) if no_data(e) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
         Ok(self)
     }
 
+    /// Return the set of commands the keyboard supports.
+    ///
+    /// Sends a `help` request the first time it is called, and caches the
+    /// result for subsequent calls. Firmware that does not support `help` will
+    /// result in an empty set of [`Capabilities`].
+    ///
+    /// ```
+    /// # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+    /// # let keyboard = MockKeyboard::new()
+    /// #     .with_command("keymap.custom", "0 0 0")
+    /// #     .with_command("palette", "0 0 0");
+    /// # let mut conn = Focus::builder().interval(0).open_transport(keyboard);
+    /// let caps = conn.capabilities()?;
+    /// if caps.has_plugin("colormap") {
+    ///     // ...offer theme editing...
+    /// }
+    /// assert!(caps.supports("keymap.custom"));
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn capabilities(&mut self) -> Result<&Capabilities> {
+        if self.capabilities.is_none() {
+            let reply = self.command("help")?;
+            self.capabilities = Some(Capabilities::from_help(&reply));
+        }
+        Ok(self.capabilities.as_ref().unwrap())
+    }
+
     /// Return the port name - if known - of the connected device.
     ///
     /// ```no_run
     /// # use kaleidoscope_focus::Focus;
-    /// # fn main() -> Result<(), std::io::Error> {
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
     /// let mut conn = Focus::create("/dev/ttyACM0").open()?;
     /// assert_eq!(conn.port_name(), Some("/dev/ttyACM0".to_string()));
     /// #   Ok(())
     /// # }
     /// ```
     pub fn port_name(&self) -> Option<String> {
-        self.port.name()
+        self.transport.name()
     }
 
-    /// Find supported devices, and return the paths to their ports.
+    /// Find supported devices.
     ///
     /// Iterates over available USB serial ports, and keeps only those that belong
-    /// to a supported keyboard. The crate only recognises Keyboardio devices as
-    /// supported keyboards.
+    /// to a supported keyboard: one in the default [`Registry`](devices::Registry).
+    /// To recognise other devices, use [`Registry::find_devices`](devices::Registry::find_devices)
+    /// with an extended registry.
+    ///
+    /// Returns an empty list if there are no supported devices, and an error
+    /// if the serial ports could not be listed.
     ///
     /// ```no_run
     /// # use kaleidoscope_focus::Focus;
-    /// let devices = Focus::find_devices().unwrap();
-    /// assert!(devices.len() > 0);
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+    /// for device in Focus::find_devices()? {
+    ///     println!("{}: {} ({:04x}:{:04x})", device.port, device.name, device.vid, device.pid);
+    /// }
+    /// #   Ok(())
+    /// # }
     /// ```
-    pub fn find_devices() -> Option<Vec<String>> {
-        #[derive(PartialEq)]
-        struct DeviceDescriptor {
-            vid: u16,
-            pid: u16,
-        }
-        impl From<&serialport::UsbPortInfo> for DeviceDescriptor {
-            fn from(port: &serialport::UsbPortInfo) -> Self {
-                Self {
-                    vid: port.vid,
-                    pid: port.pid,
-                }
-            }
-        }
+    pub fn find_devices() -> Result<Vec<devices::DeviceInfo>> {
+        devices::Registry::default().find_devices()
+    }
 
-        let supported_keyboards = [
-            // Keyboardio Model100
-            DeviceDescriptor {
-                vid: 0x3496,
-                pid: 0x0006,
-            },
-            // Keyboardio Atreus
-            DeviceDescriptor {
-                vid: 0x1209,
-                pid: 0x2303,
-            },
-            // Keyboardio Model01
-            DeviceDescriptor {
-                vid: 0x1209,
-                pid: 0x2301,
-            },
-        ];
-
-        let devices: Vec<String> = serialport::available_ports()
-            .ok()?
-            .iter()
-            .filter_map(|p| match &p.port_type {
-                serialport::SerialPortType::UsbPort(port_info) => supported_keyboards
-                    .contains(&port_info.into())
-                    .then(|| p.port_name.to_string()),
-                _ => None,
-            })
-            .collect();
-
-        if devices.is_empty() {
-            return None;
-        }
+    /// Open a connection to the supported device with the given serial number,
+    /// with the default settings.
+    ///
+    /// ```no_run
+    /// # use kaleidoscope_focus::Focus;
+    /// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+    /// let mut conn = Focus::open_by_serial("kbio01a5e3ea2a0")?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn open_by_serial(serial: &str) -> Result<Focus> {
+        Self::open_selected(&devices::Selector::new().serial(serial))
+    }
 
-        Some(devices)
+    /// Open a connection to the only supported device whose name contains
+    /// `model` - ignoring case -, with the default settings.
+    ///
+    /// Fails with [`Error::AmbiguousDevice`] if there are more than one.
+    pub fn open_by_model(model: &str) -> Result<Focus> {
+        Self::open_selected(&devices::Selector::new().model(model))
     }
 
-    fn wait_for_data(&mut self) -> Result<(), std::io::Error> {
-        while self.port.bytes_to_read()? == 0 {
-            thread::sleep(Duration::from_millis(self.interval));
-        }
-        Ok(())
+    /// Open a connection to the supported device picked by a selector, with
+    /// the default settings.
+    ///
+    /// See [`Registry::select`](devices::Registry::select) for the details.
+    pub fn open_selected(selector: &devices::Selector) -> Result<Focus> {
+        let device = devices::Registry::default().select(selector)?;
+        Focus::create(&device.port).open()
     }
 }
 
@@ -321,9 +441,11 @@ impl Focus {
 ///
 /// Use [`Focus::create`] to start building.
 pub struct FocusBuilder<'a> {
-    device: &'a str,
+    device: Option<&'a str>,
     chunk_size: usize,
     interval: u64,
+    timeout: u64,
+    reconnect: Option<ReconnectPolicy>,
 }
 
 impl FocusBuilder<'_> {
@@ -349,22 +471,171 @@ impl FocusBuilder<'_> {
         self
     }
 
+    /// Set how long to wait for a complete reply, in milliseconds.
+    ///
+    /// If the keyboard does not finish its reply within this time, requests
+    /// fail with [`Error::Timeout`]. Defaults to ten seconds.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
     /// Open a connection to the keyboard.
     ///
     /// Stops building the configuration for the [`Focus`] struct, and opens a
     /// connection to the keyboard.
     ///
     /// See [`Focus::create`] for an example.
-    pub fn open(&self) -> Result<Focus, serialport::Error> {
-        let port = serialport::new(self.device, 115200)
-            .timeout(Duration::from_millis(self.interval))
-            .open()?;
+    pub fn open(&self) -> Result<Focus> {
+        let device = self.device()?;
+        let mut focus = self.open_transport(open_port(device, self.interval)?);
+        // Pseudo-terminals - used by emulators, for example - have no control
+        // signals, do not insist on setting them.
+        focus.control_signals = focus.transport.write_data_terminal_ready(true).is_ok();
+        focus.reconnect = self
+            .reconnect
+            .clone()
+            .map(|policy| Reconnect::new(policy, device, self.interval));
+        Ok(focus)
+    }
 
-        Ok(Focus {
-            port,
+    /// Set a policy to reconnect with, when the keyboard goes away.
+    ///
+    /// Off by default, in which case requests fail with
+    /// [`Error::Disconnected`], and the connection cannot be used anymore.
+    /// Only applies to connections opened with [`FocusBuilder::open`]. See
+    /// [`ReconnectPolicy`] for details, and an example.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    fn device(&self) -> Result<&str> {
+        self.device.ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No device specified",
+            ))
+        })
+    }
+
+    /// Open a connection to the keyboard over a custom transport.
+    ///
+    /// Like [`FocusBuilder::open`], but rather than opening a serial port, uses
+    /// the given [`FocusTransport`] to talk to the keyboard. The device set
+    /// when creating the builder, if any, is ignored.
+    ///
+    /// See [`FocusTransport`] for an example.
+    pub fn open_transport(&self, transport: impl FocusTransport + 'static) -> Focus {
+        Focus {
+            transport: Box::new(transport),
             chunk_size: self.chunk_size,
             interval: self.interval,
+            timeout: self.timeout,
             progress_report: Box::new(|_| {}),
+            trace: None,
+            opened: Instant::now(),
+            control_signals: true,
+            capabilities: None,
+            reconnect: None,
+        }
+    }
+}
+
+/// Return whether a read failed only because there was no data to read yet.
+fn no_data(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Open a serial port, with the settings Focus needs.
+fn open_port(device: &str, interval: u64) -> Result<Box<dyn serialport::SerialPort>> {
+    serialport::new(device, 115200)
+        .timeout(Duration::from_millis(interval))
+        .open()
+        .map_err(|source| Error::Open {
+            device: device.to_string(),
+            source,
         })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// A transport whose other end has gone away: reads hit end of file.
+    struct Eof;
+
+    impl Read for Eof {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Eof {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl FocusTransport for Eof {
+        fn bytes_available(&mut self) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    /// A transport that never has anything to say.
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Silent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl FocusTransport for Silent {
+        fn bytes_available(&mut self) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn end_of_file_is_a_disconnect() {
+        let mut conn = Focus::builder()
+            .interval(0)
+            .timeout(5000)
+            .open_transport(Eof);
+        let started = Instant::now();
+
+        assert!(matches!(
+            conn.command("version"),
+            Err(Error::Disconnected(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn would_block_waits_for_the_timeout() {
+        let mut conn = Focus::builder()
+            .interval(0)
+            .timeout(50)
+            .open_transport(Silent);
+
+        assert!(matches!(conn.command("version"), Err(Error::Timeout)));
     }
 }
@@ -0,0 +1,497 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decoding and encoding the dynamic macros stored in `macros.map`.
+//!
+//! `macros.map` is a stream of bytecode: every macro is a list of steps - an
+//! opcode, followed by its arguments - terminated by an `END` marker. The
+//! stream itself ends with a second `END`, right after the last macro's, or
+//! at the first erased (`255`) byte where an opcode is expected. Anything
+//! beyond that is free space, and is ignored.
+//!
+//! Decoding a stream, and encoding the result gives back the same bytes, up to
+//! and including the terminator.
+//!
+//! # Examples
+//!
+//! ```
+//! # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+//! use kaleidoscope_focus::macros::{Macro, Step};
+//!
+//! # fn main() -> Result<(), kaleidoscope_focus::Error> {
+//! # let keyboard = MockKeyboard::new().with_command("macros.map", "8 11 8 12 0 0 255 255");
+//! # let mut conn = Focus::builder().interval(0).open_transport(keyboard.clone());
+//! let mut macros = conn.read_macros()?;
+//! assert_eq!(macros, vec![Macro::new(vec![Step::TapCode(11), Step::TapCode(12)])]);
+//!
+//! macros[0].steps.push(Step::Tap("Key_Spacebar".parse()?));
+//! conn.write_macros(&macros)?;
+//! # assert_eq!(
+//! #     keyboard.requests().last().unwrap().args,
+//! #     vec!["8", "11", "8", "12", "5", "0", "44", "0", "0"]
+//! # );
+//! #   Ok(())
+//! # }
+//! ```
+
+use crate::key::Key;
+use crate::{protocol, Error, Focus, Result};
+use std::fmt;
+
+/// The opcodes of macro steps, from Kaleidoscope's `MacroSteps.h`.
+mod opcode {
+    pub const END: u8 = 0;
+    pub const INTERVAL: u8 = 1;
+    pub const WAIT: u8 = 2;
+    pub const KEY_DOWN: u8 = 3;
+    pub const KEY_UP: u8 = 4;
+    pub const TAP: u8 = 5;
+    pub const KEY_CODE_DOWN: u8 = 6;
+    pub const KEY_CODE_UP: u8 = 7;
+    pub const TAP_CODE: u8 = 8;
+    pub const EXPLICIT_REPORT: u8 = 9;
+    pub const IMPLICIT_REPORT: u8 = 10;
+    pub const SEND_REPORT: u8 = 11;
+    pub const TAP_SEQUENCE: u8 = 12;
+    pub const TAP_CODE_SEQUENCE: u8 = 13;
+    /// Not an opcode, but an erased byte of EEPROM, which ends the stream.
+    pub const ERASED: u8 = 0xFF;
+}
+
+/// A single step of a [`Macro`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Step {
+    /// Set the delay between steps, in milliseconds.
+    Interval(u8),
+    /// Wait for the given number of milliseconds.
+    Wait(u8),
+    /// Press a key.
+    KeyDown(Key),
+    /// Release a key.
+    KeyUp(Key),
+    /// Press, then release a key.
+    Tap(Key),
+    /// Press a key, given by its keyboard key code alone.
+    KeyCodeDown(u8),
+    /// Release a key, given by its keyboard key code alone.
+    KeyCodeUp(u8),
+    /// Press, then release a key, given by its keyboard key code alone.
+    TapCode(u8),
+    /// Deprecated, ignored by the firmware.
+    ExplicitReport,
+    /// Deprecated, ignored by the firmware.
+    ImplicitReport,
+    /// Deprecated, ignored by the firmware.
+    SendReport,
+    /// Tap each key in turn. Must not contain `Key_NoKey`.
+    TapSequence(Vec<Key>),
+    /// Tap each keyboard key code in turn. Must not contain `0`.
+    TapCodeSequence(Vec<u8>),
+}
+
+impl Step {
+    /// Expand sequences into individual taps.
+    ///
+    /// Returns the step itself for anything but [`Step::TapSequence`] and
+    /// [`Step::TapCodeSequence`], which are turned into [`Step::Tap`] and
+    /// [`Step::TapCode`] steps, respectively.
+    pub fn explode(&self) -> Vec<Step> {
+        match self {
+            Step::TapSequence(keys) => keys.iter().map(|&k| Step::Tap(k)).collect(),
+            Step::TapCodeSequence(codes) => codes.iter().map(|&c| Step::TapCode(c)).collect(),
+            step => vec![step.clone()],
+        }
+    }
+
+    fn encode(&self, bytes: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Step::Interval(ms) => bytes.extend([opcode::INTERVAL, *ms]),
+            Step::Wait(ms) => bytes.extend([opcode::WAIT, *ms]),
+            Step::KeyDown(key) => encode_key(bytes, opcode::KEY_DOWN, *key)?,
+            Step::KeyUp(key) => encode_key(bytes, opcode::KEY_UP, *key)?,
+            Step::Tap(key) => encode_key(bytes, opcode::TAP, *key)?,
+            Step::KeyCodeDown(code) => bytes.extend([opcode::KEY_CODE_DOWN, *code]),
+            Step::KeyCodeUp(code) => bytes.extend([opcode::KEY_CODE_UP, *code]),
+            Step::TapCode(code) => bytes.extend([opcode::TAP_CODE, *code]),
+            Step::ExplicitReport => bytes.push(opcode::EXPLICIT_REPORT),
+            Step::ImplicitReport => bytes.push(opcode::IMPLICIT_REPORT),
+            Step::SendReport => bytes.push(opcode::SEND_REPORT),
+            Step::TapSequence(keys) => {
+                if keys.contains(&Key::NO_KEY) {
+                    return Err(Error::InvalidValue(
+                        "A tap sequence cannot contain Key_NoKey".to_string(),
+                    ));
+                }
+                bytes.push(opcode::TAP_SEQUENCE);
+                for &key in keys {
+                    bytes.extend(u16::try_from(key)?.to_be_bytes());
+                }
+                bytes.extend([0, 0]);
+            }
+            Step::TapCodeSequence(codes) => {
+                if codes.contains(&0) {
+                    return Err(Error::InvalidValue(
+                        "A tap code sequence cannot contain 0".to_string(),
+                    ));
+                }
+                bytes.push(opcode::TAP_CODE_SEQUENCE);
+                bytes.extend(codes);
+                bytes.push(0);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |items: Vec<String>| items.join(", ");
+        match self {
+            Step::Interval(ms) => write!(f, "Interval({})", ms),
+            Step::Wait(ms) => write!(f, "Wait({})", ms),
+            Step::KeyDown(key) => write!(f, "KeyDown({})", key),
+            Step::KeyUp(key) => write!(f, "KeyUp({})", key),
+            Step::Tap(key) => write!(f, "Tap({})", key),
+            Step::KeyCodeDown(code) => write!(f, "KeyCodeDown({})", code),
+            Step::KeyCodeUp(code) => write!(f, "KeyCodeUp({})", code),
+            Step::TapCode(code) => write!(f, "TapCode({})", code),
+            Step::ExplicitReport => write!(f, "ExplicitReport"),
+            Step::ImplicitReport => write!(f, "ImplicitReport"),
+            Step::SendReport => write!(f, "SendReport"),
+            Step::TapSequence(keys) => write!(
+                f,
+                "TapSequence({})",
+                join(keys.iter().map(|k| k.to_string()).collect())
+            ),
+            Step::TapCodeSequence(codes) => write!(
+                f,
+                "TapCodeSequence({})",
+                join(codes.iter().map(|c| c.to_string()).collect())
+            ),
+        }
+    }
+}
+
+/// A dynamic macro: a list of steps.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Macro {
+    /// The steps of the macro.
+    pub steps: Vec<Step>,
+}
+
+impl Macro {
+    /// Create a new macro from a list of steps.
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self { steps }
+    }
+
+    /// Return a copy of the macro, with every sequence expanded into taps.
+    ///
+    /// See [`Step::explode`].
+    pub fn explode(&self) -> Self {
+        Self::new(self.steps.iter().flat_map(Step::explode).collect())
+    }
+}
+
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps: Vec<String> = self.steps.iter().map(|s| s.to_string()).collect();
+        write!(f, "{}", steps.join(" "))
+    }
+}
+
+/// Decode a `macros.map` bytecode stream into a list of macros.
+///
+/// Follows the firmware: the stream ends at the first `END` that immediately
+/// follows another, or at the first erased byte (`255`) in place of an opcode.
+/// A macro cut short by an erased byte keeps the steps before it. Returns an
+/// [`Error::InvalidReply`] - including the byte offset - if the stream contains
+/// an unknown opcode, or ends prematurely.
+///
+/// # Examples
+///
+/// ```
+/// use kaleidoscope_focus::macros::{self, Step};
+///
+/// let bytes = [2, 100, 12, 0, 4, 0, 5, 0, 0, 0, 3, 8, 0, 0, 0, 255];
+/// let decoded = macros::decode(&bytes)?;
+/// assert_eq!(decoded.len(), 2);
+/// assert_eq!(decoded[0].steps[0], Step::Wait(100));
+/// assert_eq!(macros::encode(&decoded)?, &bytes[..15]);
+///
+/// assert!(macros::decode(&[255, 255, 255]).unwrap().is_empty());
+/// assert!(macros::decode(&[42, 0, 0]).is_err());
+/// # Ok::<(), kaleidoscope_focus::Error>(())
+/// ```
+pub fn decode(bytes: &[u8]) -> Result<Vec<Macro>> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let mut macros = vec![];
+    let mut steps = vec![];
+    let mut previous_ended = false;
+
+    loop {
+        let offset = reader.pos;
+        let op = reader.byte()?;
+        if op == opcode::ERASED {
+            if !steps.is_empty() {
+                macros.push(Macro::new(steps));
+            }
+            return Ok(macros);
+        }
+        if op == opcode::END {
+            if previous_ended {
+                return Ok(macros);
+            }
+            macros.push(Macro::new(std::mem::take(&mut steps)));
+            previous_ended = true;
+            continue;
+        }
+        previous_ended = false;
+
+        let step = match op {
+            opcode::INTERVAL => Step::Interval(reader.byte()?),
+            opcode::WAIT => Step::Wait(reader.byte()?),
+            opcode::KEY_DOWN => Step::KeyDown(reader.key()?),
+            opcode::KEY_UP => Step::KeyUp(reader.key()?),
+            opcode::TAP => Step::Tap(reader.key()?),
+            opcode::KEY_CODE_DOWN => Step::KeyCodeDown(reader.byte()?),
+            opcode::KEY_CODE_UP => Step::KeyCodeUp(reader.byte()?),
+            opcode::TAP_CODE => Step::TapCode(reader.byte()?),
+            opcode::EXPLICIT_REPORT => Step::ExplicitReport,
+            opcode::IMPLICIT_REPORT => Step::ImplicitReport,
+            opcode::SEND_REPORT => Step::SendReport,
+            opcode::TAP_SEQUENCE => {
+                let mut keys = vec![];
+                loop {
+                    match reader.key()? {
+                        Key::NO_KEY => break,
+                        key => keys.push(key),
+                    }
+                }
+                Step::TapSequence(keys)
+            }
+            opcode::TAP_CODE_SEQUENCE => {
+                let mut codes = vec![];
+                loop {
+                    match reader.byte()? {
+                        0 => break,
+                        code => codes.push(code),
+                    }
+                }
+                Step::TapCodeSequence(codes)
+            }
+            op => {
+                return Err(invalid(format!(
+                    "unknown opcode {} at offset {}",
+                    op, offset
+                )))
+            }
+        };
+        steps.push(step);
+    }
+}
+
+/// Encode a list of macros into a `macros.map` bytecode stream.
+///
+/// Since two `END` markers in a row terminate the stream, only the first macro
+/// may be empty: returns an [`Error::InvalidValue`] for any other empty macro,
+/// or for sequences containing their own terminator. An empty list is encoded
+/// the same way as a single, empty macro.
+pub fn encode(macros: &[Macro]) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+
+    for (i, m) in macros.iter().enumerate() {
+        if i > 0 && m.steps.is_empty() {
+            return Err(Error::InvalidValue(format!(
+                "Macro {} is empty, only the first macro may be empty",
+                i
+            )));
+        }
+        for step in &m.steps {
+            step.encode(&mut bytes)?;
+        }
+        bytes.push(opcode::END);
+    }
+    if macros.is_empty() {
+        bytes.push(opcode::END);
+    }
+    bytes.push(opcode::END);
+
+    Ok(bytes)
+}
+
+fn encode_key(bytes: &mut Vec<u8>, op: u8, key: Key) -> Result<()> {
+    bytes.push(op);
+    bytes.extend(u16::try_from(key)?.to_be_bytes());
+    Ok(())
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidReply {
+        command: "macros.map".to_string(),
+        reason,
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8> {
+        let byte = self.bytes.get(self.pos).copied().ok_or_else(|| {
+            invalid(format!(
+                "unexpected end of the stream at offset {}",
+                self.pos
+            ))
+        })?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    /// Read a key: its flags first, then its key code.
+    fn key(&mut self) -> Result<Key> {
+        let flags = self.byte()?;
+        let code = self.byte()?;
+        Ok(Key::from(u16::from_be_bytes([flags, code])))
+    }
+}
+
+impl Focus {
+    /// Read the dynamic macros from the keyboard.
+    ///
+    /// See the [module documentation](crate::macros) for an example.
+    pub fn read_macros(&mut self) -> Result<Vec<Macro>> {
+        let reply = self.command("macros.map")?;
+        if reply.is_empty() {
+            return Err(Error::UnknownCommand("macros.map".to_string()));
+        }
+        decode(&protocol::parse_numbers::<u8>("macros.map", &reply)?)
+    }
+
+    /// Write dynamic macros to the keyboard.
+    ///
+    /// See [`encode`] for the restrictions on what can be written, and the
+    /// [module documentation](crate::macros) for an example.
+    pub fn write_macros(&mut self, macros: &[Macro]) -> Result<()> {
+        let args = protocol::format_numbers(encode(macros)?);
+        self.request("macros.map", Some(&args))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_step_round_trips() {
+        let key = Key::from(0x0804);
+        let macros = vec![
+            Macro::new(vec![]),
+            Macro::new(vec![
+                Step::Interval(10),
+                Step::Wait(200),
+                Step::KeyDown(key),
+                Step::KeyUp(key),
+                Step::Tap(Key::Transparent),
+                Step::KeyCodeDown(4),
+                Step::KeyCodeUp(4),
+                Step::TapCode(0xFF),
+            ]),
+            Macro::new(vec![
+                Step::ExplicitReport,
+                Step::ImplicitReport,
+                Step::SendReport,
+                Step::TapSequence(vec![key, Key::TapDance(3)]),
+                Step::TapSequence(vec![]),
+                Step::TapCodeSequence(vec![4, 5, 6]),
+            ]),
+        ];
+        let bytes = encode(&macros).unwrap();
+        assert_eq!(&bytes[bytes.len() - 2..], [0, 0]);
+        assert_eq!(decode(&bytes).unwrap(), macros);
+    }
+
+    #[test]
+    fn empty_streams() {
+        assert_eq!(encode(&[]).unwrap(), [0, 0]);
+        assert_eq!(decode(&[0, 0]).unwrap(), vec![Macro::new(vec![])]);
+        assert!(matches!(decode(&[]), Err(Error::InvalidReply { .. })));
+    }
+
+    #[test]
+    fn malformed_streams_report_the_offset() {
+        let reason = |bytes: &[u8]| match decode(bytes) {
+            Err(Error::InvalidReply { reason, .. }) => reason,
+            result => panic!("{:?} decoded to {:?}", bytes, result),
+        };
+        assert_eq!(reason(&[8, 11, 42, 0]), "unknown opcode 42 at offset 2");
+        assert_eq!(
+            reason(&[8, 11, 0]),
+            "unexpected end of the stream at offset 3"
+        );
+        assert_eq!(reason(&[5, 0]), "unexpected end of the stream at offset 2");
+        assert_eq!(
+            reason(&[12, 0, 4]),
+            "unexpected end of the stream at offset 3"
+        );
+        assert_eq!(
+            reason(&[13, 4, 5]),
+            "unexpected end of the stream at offset 3"
+        );
+    }
+
+    #[test]
+    fn unencodable_macros() {
+        let invalid = |macros: Vec<Macro>| matches!(encode(&macros), Err(Error::InvalidValue(_)));
+        assert!(invalid(vec![
+            Macro::new(vec![Step::TapCode(4)]),
+            Macro::new(vec![])
+        ]));
+        assert!(invalid(vec![Macro::new(vec![Step::TapSequence(vec![
+            Key::NO_KEY
+        ])])]));
+        assert!(invalid(vec![Macro::new(vec![Step::TapCodeSequence(
+            vec![4, 0]
+        )])]));
+        assert!(invalid(vec![Macro::new(vec![Step::Tap(Key::TapDance(
+            16
+        ))])]));
+    }
+
+    #[test]
+    fn erased_bytes_end_the_stream() {
+        assert_eq!(decode(&[0xFF; 64]).unwrap(), vec![]);
+        assert_eq!(
+            decode(&[8, 11, 0, 0xFF, 0xFF]).unwrap(),
+            vec![Macro::new(vec![Step::TapCode(11)])]
+        );
+        assert_eq!(
+            decode(&[8, 11, 0, 8, 12, 0xFF]).unwrap(),
+            vec![
+                Macro::new(vec![Step::TapCode(11)]),
+                Macro::new(vec![Step::TapCode(12)])
+            ]
+        );
+        // As an argument, 255 is just a number.
+        assert_eq!(
+            decode(&[8, 0xFF, 0, 0]).unwrap(),
+            vec![Macro::new(vec![Step::TapCode(0xFF)])]
+        );
+    }
+}
@@ -0,0 +1,86 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{Error, Result};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Format a request, the way the keyboard expects it.
+pub(crate) fn format_request(command: &str, args: Option<&[String]>) -> String {
+    format!("{} {}\n", command, args.unwrap_or_default().join(" "))
+}
+
+/// Parse a whitespace separated list of numbers, the reply to `command`.
+pub(crate) fn parse_numbers<T: FromStr>(command: &str, reply: &str) -> Result<Vec<T>> {
+    reply
+        .split_whitespace()
+        .map(|n| {
+            n.parse().map_err(|_| Error::InvalidReply {
+                command: command.to_string(),
+                reason: format!("`{}` is not a valid number", n),
+            })
+        })
+        .collect()
+}
+
+/// Format a list of numbers as request arguments.
+pub(crate) fn format_numbers<T: Display>(numbers: impl IntoIterator<Item = T>) -> Vec<String> {
+    numbers.into_iter().map(|n| n.to_string()).collect()
+}
+
+/// Collects the bytes of a reply, until the terminating `.` line arrives.
+///
+/// Replies from the keyboard consist of any number of lines, followed by a
+/// line containing a single `.`. Lines may end with either `\r\n` or `\n`.
+#[derive(Default)]
+pub(crate) struct ReplyReader {
+    buffer: Vec<u8>,
+    scanned: usize,
+}
+
+impl ReplyReader {
+    /// Feed data received from the keyboard to the reader.
+    ///
+    /// Returns the reply - without the terminator and empty lines - once it is
+    /// complete, or `None` if more data is needed. Anything following the
+    /// terminator is discarded.
+    pub fn feed(&mut self, data: &[u8]) -> Option<Result<String>> {
+        self.buffer.extend_from_slice(data);
+
+        while let Some(pos) = self.buffer[self.scanned..].iter().position(|&b| b == b'\n') {
+            let end = self.scanned + pos;
+            let line = &self.buffer[self.scanned..end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+            if line == b"." {
+                self.buffer.truncate(self.scanned);
+                let reply = std::mem::take(&mut self.buffer);
+                self.scanned = 0;
+                return Some(Self::parse(reply));
+            }
+            self.scanned = end + 1;
+        }
+
+        None
+    }
+
+    fn parse(reply: Vec<u8>) -> Result<String> {
+        Ok(String::from_utf8(reply)?
+            .lines()
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<&str>>()
+            .join("\n"))
+    }
+}
@@ -0,0 +1,325 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::devices::{Registry, Selector};
+use crate::{Focus, FocusTransport, Result};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Idempotent = Arc<dyn Fn(&str, Option<&[String]>) -> bool + Send + Sync>;
+type Opener = Box<dyn FnMut(&str) -> Result<Box<dyn FocusTransport>>>;
+
+/// How to reconnect to a keyboard that went away.
+///
+/// Keyboards go away when they reset: after flashing new firmware, or
+/// changing some of the `hardware.*` settings, for example. With a reconnect
+/// policy set with [`FocusBuilder::reconnect`](crate::FocusBuilder::reconnect),
+/// a request failing with [`Error::Disconnected`](crate::Error::Disconnected)
+/// makes the connection wait for the same device - matched by its USB serial
+/// number, or if it has none, its port - to come back, and reopen it. Devices
+/// are matched by serial number only if they are known to the
+/// [registry](ReconnectPolicy::registry) of the policy.
+///
+/// If the request that failed is idempotent, it is then sent again. Otherwise
+/// the error is returned, and the connection can be used for further
+/// requests. By default, requests without arguments - those reading settings -
+/// are considered idempotent.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use kaleidoscope_focus::{Focus, ReconnectPolicy};
+/// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+/// let mut conn = Focus::create("/dev/ttyACM0")
+///     .reconnect(
+///         ReconnectPolicy::new()
+///             .timeout(30000)
+///             .idempotent(|command, args| args.is_none() && command != "hardware.reboot"),
+///     )
+///     .open()?;
+/// let version = conn.command("version")?;
+/// #   Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ReconnectPolicy {
+    pub(crate) timeout: Duration,
+    pub(crate) retries: usize,
+    pub(crate) idempotent: Idempotent,
+    pub(crate) registry: Registry,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retries: 1,
+            idempotent: Arc::new(|_, args| args.map_or(true, |args| args.is_empty())),
+            registry: Registry::default(),
+        }
+    }
+}
+
+impl fmt::Debug for ReconnectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectPolicy")
+            .field("timeout", &self.timeout)
+            .field("retries", &self.retries)
+            .field("registry", &self.registry)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReconnectPolicy {
+    /// Create the default policy: wait ten seconds for the keyboard to come
+    /// back, and retry idempotent requests once.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how long to wait for the keyboard to come back, in milliseconds.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = Duration::from_millis(timeout);
+        self
+    }
+
+    /// Set how many times a single request may be retried. Setting it to 0
+    /// reconnects, but never retries.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the function deciding whether a request - given its command and
+    /// arguments - is safe to send again.
+    pub fn idempotent(
+        mut self,
+        idempotent: impl Fn(&str, Option<&[String]>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.idempotent = Arc::new(idempotent);
+        self
+    }
+
+    /// Set the registry of devices to look for the keyboard among. The
+    /// default registry is used otherwise.
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = registry;
+        self
+    }
+}
+
+/// What a connection needs to know to reconnect.
+pub(crate) struct Reconnect {
+    pub(crate) policy: ReconnectPolicy,
+    /// The port the keyboard was last seen on.
+    pub(crate) port: String,
+    /// The USB serial number of the keyboard, if it has one.
+    pub(crate) serial: Option<String>,
+    /// Opens the port the keyboard came back on.
+    open: Opener,
+}
+
+impl Reconnect {
+    pub(crate) fn new(policy: ReconnectPolicy, port: &str, interval: u64) -> Self {
+        let serial = policy
+            .registry
+            .device_at(port)
+            .ok()
+            .flatten()
+            .and_then(|d| d.serial_number);
+
+        Self {
+            policy,
+            port: port.to_string(),
+            serial,
+            open: Box::new(move |port| Ok(Box::new(crate::open_port(port, interval)?))),
+        }
+    }
+
+    /// Find the port the keyboard is on now, if it is connected.
+    fn find_port(&self) -> Option<String> {
+        let serial = match &self.serial {
+            Some(serial) => serial,
+            None => return Some(self.port.clone()),
+        };
+        self.policy
+            .registry
+            .select(&Selector::new().serial(serial))
+            .ok()
+            .map(|d| d.port)
+    }
+}
+
+impl Focus {
+    /// Wait for the keyboard to come back, and reopen it. Returns whether it
+    /// succeeded within the timeout of the policy.
+    pub(crate) fn reconnect(&mut self) -> bool {
+        let reconnect = match &mut self.reconnect {
+            Some(reconnect) => reconnect,
+            None => return false,
+        };
+        let deadline = Instant::now() + reconnect.policy.timeout;
+        // Close the port first: while it is open, the device cannot come back
+        // on the same one.
+        self.transport = Box::new(Closed(reconnect.port.clone()));
+
+        loop {
+            if let Some(port) = reconnect.find_port() {
+                if let Ok(opened) = (reconnect.open)(&port) {
+                    reconnect.port = port;
+                    self.transport = opened;
+                    self.control_signals = self.transport.write_data_terminal_ready(true).is_ok();
+                    // The firmware may have changed while the keyboard was away.
+                    self.capabilities = None;
+                    return true;
+                }
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+/// Stands in for the port of a keyboard that went away, and did not come back.
+struct Closed(String);
+
+impl Closed {
+    fn error() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotConnected,
+            "The keyboard did not come back",
+        )
+    }
+}
+
+impl io::Read for Closed {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(Self::error())
+    }
+}
+
+impl io::Write for Closed {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(Self::error())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Err(Self::error())
+    }
+}
+
+impl FocusTransport for Closed {
+    fn bytes_available(&mut self) -> io::Result<usize> {
+        Err(Self::error())
+    }
+
+    fn name(&self) -> Option<String> {
+        Some(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockKeyboard;
+    use crate::Error;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Open a connection to a keyboard that went away, and reconnect with
+    /// `open`, counting how many times it was called.
+    fn gone(
+        policy: ReconnectPolicy,
+        mut open: impl FnMut() -> Result<Box<dyn FocusTransport>> + 'static,
+    ) -> (Focus, Rc<Cell<usize>>) {
+        let opened = Rc::new(Cell::new(0));
+        let counter = opened.clone();
+        let mut conn = Focus::builder()
+            .interval(0)
+            .open_transport(Closed("keyboard".to_string()));
+        conn.reconnect = Some(Reconnect {
+            policy,
+            port: "keyboard".to_string(),
+            serial: None,
+            open: Box::new(move |_| {
+                counter.set(counter.get() + 1);
+                open()
+            }),
+        });
+        (conn, opened)
+    }
+
+    #[test]
+    fn idempotent_requests_are_retried_once_reconnected() {
+        let keyboard = MockKeyboard::new().with_command("version", "1.0");
+        let back = keyboard.clone();
+        let (mut conn, opened) = gone(ReconnectPolicy::new(), move || Ok(Box::new(back.clone())));
+
+        assert_eq!(conn.command("version").unwrap(), "1.0");
+        assert_eq!(opened.get(), 1);
+        assert_eq!(keyboard.requests().len(), 1);
+    }
+
+    #[test]
+    fn requests_with_arguments_are_not_resent() {
+        let keyboard = MockKeyboard::new().with_command("led.mode", "0");
+        let back = keyboard.clone();
+        let (mut conn, opened) = gone(ReconnectPolicy::new(), move || Ok(Box::new(back.clone())));
+
+        let args = vec!["1".to_string()];
+        assert!(matches!(
+            conn.request("led.mode", Some(&args)),
+            Err(Error::Disconnected(_))
+        ));
+        assert_eq!(opened.get(), 1);
+        assert!(keyboard.requests().is_empty());
+
+        // The connection was reopened, and can be used again.
+        conn.request("led.mode", Some(&args)).unwrap();
+        assert_eq!(keyboard.get("led.mode").unwrap(), "1");
+    }
+
+    #[test]
+    fn running_out_of_retries_is_a_disconnect() {
+        let (mut conn, opened) = gone(ReconnectPolicy::new().retries(2), || {
+            Ok(Box::new(Closed("keyboard".to_string())))
+        });
+
+        assert!(matches!(
+            conn.command("version"),
+            Err(Error::Disconnected(_))
+        ));
+        // Reconnected after the first attempt, and after each of the retries.
+        assert_eq!(opened.get(), 3);
+    }
+
+    #[test]
+    fn keyboards_not_coming_back_are_a_disconnect() {
+        let (mut conn, opened) = gone(ReconnectPolicy::new().timeout(0), || {
+            Err(Error::Disconnected(Closed::error()))
+        });
+
+        assert!(matches!(
+            conn.command("version"),
+            Err(Error::Disconnected(_))
+        ));
+        assert_eq!(opened.get(), 1);
+    }
+}
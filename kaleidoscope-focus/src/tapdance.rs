@@ -0,0 +1,251 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decoding and encoding the dynamic tap-dances stored in `tapdance.map`.
+//!
+//! `tapdance.map` is a list of keys: every tap-dance is the list of keys to
+//! use for one, two, three and more taps, terminated by `Key_NoKey`. The map
+//! itself ends with a second `Key_NoKey`, right after the last tap-dance's.
+//!
+//! The reply to `tapdance.map` contains the whole storage area reserved for
+//! tap-dances, so its length is also the most the keyboard can hold.
+//!
+//! # Examples
+//!
+//! ```
+//! # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+//! use kaleidoscope_focus::{key::Key, tapdance::TapDance};
+//!
+//! # fn main() -> Result<(), kaleidoscope_focus::Error> {
+//! # let keyboard = MockKeyboard::new().with_command("tapdance.map", "4 5 0 0 65535 65535");
+//! # let mut conn = Focus::builder().interval(0).open_transport(keyboard.clone());
+//! let mut dances = conn.read_tapdances()?;
+//! assert_eq!(dances, vec![TapDance::new(vec![Key::from(4), Key::from(5)])]);
+//!
+//! dances[0].actions.push("Key_C".parse()?);
+//! conn.write_tapdances(&dances)?;
+//! # assert_eq!(keyboard.requests().last().unwrap().args, vec!["4", "5", "6", "0", "0"]);
+//!
+//! dances.push(TapDance::new(vec![Key::from(7); 4]));
+//! assert!(conn.write_tapdances(&dances).is_err());
+//! #   Ok(())
+//! # }
+//! ```
+
+use crate::key::Key;
+use crate::{protocol, Error, Focus, Result};
+use std::fmt;
+
+/// A single tap-dance: the key to use for each number of taps.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TapDance {
+    /// The keys to use for one, two, three and more taps, in this order.
+    pub actions: Vec<Key>,
+}
+
+impl TapDance {
+    /// Create a new tap-dance from a list of actions.
+    pub fn new(actions: Vec<Key>) -> Self {
+        Self { actions }
+    }
+
+    /// Return the key to use after the given number of taps, if any.
+    ///
+    /// Tap counts start at one.
+    pub fn action(&self, taps: usize) -> Option<Key> {
+        self.actions.get(taps.checked_sub(1)?).copied()
+    }
+}
+
+impl fmt::Display for TapDance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let actions: Vec<String> = self.actions.iter().map(|k| k.to_string()).collect();
+        write!(f, "{}", actions.join(" "))
+    }
+}
+
+/// Decode the contents of `tapdance.map` into a list of tap-dances.
+///
+/// Follows the firmware: the map ends at the first `Key_NoKey` that
+/// immediately follows another, and a tap-dance left unterminated at the end
+/// of the storage area is ignored.
+///
+/// # Examples
+///
+/// ```
+/// use kaleidoscope_focus::{key::Key, tapdance};
+///
+/// let keys: Vec<Key> = [4, 5, 0, 6, 0, 0, 7].into_iter().map(Key::from).collect();
+/// let dances = tapdance::decode(&keys);
+/// assert_eq!(dances.len(), 2);
+/// assert_eq!(dances[1].action(1), Some(Key::from(6)));
+/// assert_eq!(tapdance::encode(&dances)?, &keys[..6]);
+/// # Ok::<(), kaleidoscope_focus::Error>(())
+/// ```
+pub fn decode(keys: &[Key]) -> Vec<TapDance> {
+    let mut dances = vec![];
+    let mut actions = vec![];
+    let mut previous_ended = false;
+
+    for &key in keys {
+        if key == Key::NO_KEY {
+            if previous_ended {
+                break;
+            }
+            dances.push(TapDance::new(std::mem::take(&mut actions)));
+            previous_ended = true;
+        } else {
+            actions.push(key);
+            previous_ended = false;
+        }
+    }
+
+    dances
+}
+
+/// Encode a list of tap-dances into the contents of `tapdance.map`.
+///
+/// Since two `Key_NoKey`s in a row terminate the map, only the first
+/// tap-dance may be empty, and no action may be `Key_NoKey`: returns an
+/// [`Error::InvalidValue`] otherwise. An empty list is encoded the same way as
+/// a single, empty tap-dance.
+pub fn encode(dances: &[TapDance]) -> Result<Vec<Key>> {
+    let mut keys = vec![];
+
+    for (i, dance) in dances.iter().enumerate() {
+        if i > 0 && dance.actions.is_empty() {
+            return Err(Error::InvalidValue(format!(
+                "Tap-dance {} is empty, only the first tap-dance may be empty",
+                i
+            )));
+        }
+        if dance.actions.contains(&Key::NO_KEY) {
+            return Err(Error::InvalidValue(format!(
+                "Tap-dance {} contains Key_NoKey",
+                i
+            )));
+        }
+        keys.extend(&dance.actions);
+        keys.push(Key::NO_KEY);
+    }
+    if dances.is_empty() {
+        keys.push(Key::NO_KEY);
+    }
+    keys.push(Key::NO_KEY);
+
+    Ok(keys)
+}
+
+/// Check that a list of tap-dances fits into a storage area of `size` keys.
+///
+/// Returns an [`Error::InvalidValue`] if it does not, or if the tap-dances
+/// cannot be encoded at all. See [`encode`].
+pub fn validate(dances: &[TapDance], size: usize) -> Result<()> {
+    let len = encode(dances)?.len();
+    if len > size {
+        return Err(Error::InvalidValue(format!(
+            "The tap-dances need room for {} keys, but the keyboard only has room for {}",
+            len, size
+        )));
+    }
+    Ok(())
+}
+
+impl Focus {
+    /// Read the dynamic tap-dances from the keyboard.
+    ///
+    /// See the [module documentation](crate::tapdance) for an example.
+    pub fn read_tapdances(&mut self) -> Result<Vec<TapDance>> {
+        Ok(decode(&self.read_tapdance_storage()?))
+    }
+
+    /// Write dynamic tap-dances to the keyboard.
+    ///
+    /// Validates the tap-dances before writing, against the storage size the
+    /// keyboard reports. See [`validate`].
+    ///
+    /// See the [module documentation](crate::tapdance) for an example.
+    pub fn write_tapdances(&mut self, dances: &[TapDance]) -> Result<()> {
+        let size = self.read_tapdance_storage()?.len();
+        validate(dances, size)?;
+
+        let codes = encode(dances)?
+            .into_iter()
+            .map(u16::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let args = protocol::format_numbers(codes);
+        self.request("tapdance.map", Some(&args))?;
+        Ok(())
+    }
+
+    fn read_tapdance_storage(&mut self) -> Result<Vec<Key>> {
+        let reply = self.command("tapdance.map")?;
+        if reply.is_empty() {
+            return Err(Error::UnknownCommand("tapdance.map".to_string()));
+        }
+        Ok(protocol::parse_numbers::<u16>("tapdance.map", &reply)?
+            .into_iter()
+            .map(Key::from)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(codes: &[u16]) -> Vec<Key> {
+        codes.iter().copied().map(Key::from).collect()
+    }
+
+    #[test]
+    fn tapdances_round_trip() {
+        let dances = vec![
+            TapDance::new(vec![]),
+            TapDance::new(keys(&[4, 5, 6])),
+            TapDance::new(vec![Key::Transparent, Key::TapDance(15)]),
+        ];
+        let encoded = encode(&dances).unwrap();
+        assert_eq!(encoded.len(), 9);
+        assert_eq!(decode(&encoded), dances);
+    }
+
+    #[test]
+    fn empty_maps() {
+        assert_eq!(decode(&[]), vec![]);
+        assert_eq!(decode(&keys(&[0, 0, 0])), vec![TapDance::new(vec![])]);
+        assert_eq!(encode(&[]).unwrap(), keys(&[0, 0]));
+    }
+
+    #[test]
+    fn unterminated_tapdances_are_ignored() {
+        assert_eq!(decode(&keys(&[4, 5])), vec![]);
+        assert_eq!(decode(&keys(&[4, 0, 5])), vec![TapDance::new(keys(&[4]))]);
+    }
+
+    #[test]
+    fn unencodable_tapdances() {
+        assert!(encode(&[TapDance::new(keys(&[4])), TapDance::new(vec![])]).is_err());
+        assert!(encode(&[TapDance::new(keys(&[4, 0]))]).is_err());
+    }
+
+    #[test]
+    fn tapdances_must_fit_into_the_storage() {
+        let dances = [TapDance::new(keys(&[4, 5]))];
+        assert!(validate(&dances, 4).is_ok());
+        assert!(matches!(validate(&dances, 3), Err(Error::InvalidValue(_))));
+        assert!(validate(&[], 1).is_err());
+    }
+}
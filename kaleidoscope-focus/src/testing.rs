@@ -0,0 +1,519 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Helpers for testing code built on top of this crate.
+//!
+//! Only available when the `testing` feature is enabled.
+
+use crate::trace::{Direction, TraceEvent};
+use crate::{FocusTransport, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// An in-memory keyboard, speaking Focus.
+///
+/// The mock keyboard holds a table of Focus commands, and their current
+/// values. Requests without arguments reply with the value of the command,
+/// requests with arguments update it. Unknown commands get an empty reply, and
+/// `help` - unless overridden - lists all known commands, just like a real
+/// keyboard would.
+///
+/// Every request the keyboard receives is recorded, and can be inspected with
+/// [`MockKeyboard::requests`]. Clones of a `MockKeyboard` share their state,
+/// so a clone can be kept around for inspection after the original has been
+/// handed over to [`Focus`](crate::Focus).
+///
+/// # Examples
+///
+/// ```
+/// # use kaleidoscope_focus::Focus;
+/// use kaleidoscope_focus::testing::MockKeyboard;
+///
+/// let keyboard = MockKeyboard::new().with_command("settings.version", "1");
+/// let mut conn = Focus::builder()
+///     .interval(0)
+///     .open_transport(keyboard.clone());
+///
+/// assert_eq!(conn.command("settings.version")?, "1");
+/// conn.request("settings.version", Some(&["2".to_string()]))?;
+/// assert_eq!(keyboard.get("settings.version"), Some("2".to_string()));
+///
+/// assert_eq!(keyboard.requests().len(), 2);
+/// assert_eq!(keyboard.requests()[1].args, vec!["2"]);
+/// # Ok::<(), kaleidoscope_focus::Error>(())
+/// ```
+#[derive(Clone, Default)]
+pub struct MockKeyboard {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    commands: BTreeMap<String, String>,
+    requests: Vec<MockRequest>,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+    #[cfg(feature = "async")]
+    reader: Option<std::task::Waker>,
+}
+
+/// A request received by a [`MockKeyboard`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockRequest {
+    /// The command part of the request.
+    pub command: String,
+    /// The arguments of the request, if any.
+    pub args: Vec<String>,
+}
+
+impl MockKeyboard {
+    /// Create a new mock keyboard, without any commands.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a command to the keyboard, with an initial value.
+    ///
+    /// Replies spanning multiple lines should separate them with `\n`.
+    pub fn with_command(self, command: &str, value: &str) -> Self {
+        self.set(command, value);
+        self
+    }
+
+    /// Set the value of a command.
+    pub fn set(&self, command: &str, value: &str) {
+        self.state()
+            .commands
+            .insert(command.to_string(), value.to_string());
+    }
+
+    /// Return the current value of a command, if known.
+    pub fn get(&self, command: &str) -> Option<String> {
+        self.state().commands.get(command).cloned()
+    }
+
+    /// Return every request the keyboard received so far, in order.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state().requests.clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MockState {
+    fn process(&mut self, line: &str) {
+        let mut words = line.split_whitespace().map(|w| w.to_string());
+        let command = words.next().unwrap_or_default();
+        let args: Vec<String> = words.collect();
+
+        let reply = if args.is_empty() {
+            match self.commands.get(&command) {
+                Some(value) => value.clone(),
+                None if command == "help" => self
+                    .commands
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                None => String::new(),
+            }
+        } else {
+            if let Some(value) = self.commands.get_mut(&command) {
+                *value = args.join(" ");
+            }
+            String::new()
+        };
+
+        for line in reply.lines().filter(|l| !l.is_empty()) {
+            self.output.extend(line.as_bytes());
+            self.output.extend(b"\r\n");
+        }
+        self.output.extend(b".\r\n");
+
+        self.requests.push(MockRequest { command, args });
+
+        #[cfg(feature = "async")]
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Read for MockKeyboard {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.output.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+
+        let n = buf.len().min(state.output.len());
+        for (dst, src) in buf.iter_mut().zip(state.output.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MockKeyboard {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        state.input.extend(buf);
+
+        while let Some(pos) = state.input.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = state.input.drain(..=pos).collect();
+            state.process(&String::from_utf8_lossy(&line));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FocusTransport for MockKeyboard {
+    fn bytes_available(&mut self) -> io::Result<usize> {
+        Ok(self.state().output.len())
+    }
+
+    fn name(&self) -> Option<String> {
+        Some("mock".to_string())
+    }
+}
+
+/// A transport replaying the traffic recorded in a capture.
+///
+/// Built from the events of a capture - see the [`trace`](crate::trace)
+/// module -, the replay expects the requests sent to it to match the recorded
+/// ones byte for byte, and answers each with the recorded reply. How the data
+/// was split into chunks does not matter.
+///
+/// As soon as a request diverges from the capture, or goes beyond its end,
+/// writing fails with an [`io::ErrorKind::InvalidData`] error describing the
+/// difference, and so does every write after it. Like [`MockKeyboard`], clones
+/// share their state, so one can be kept around to check that the whole
+/// capture was replayed.
+///
+/// # Examples
+///
+/// ```
+/// # use kaleidoscope_focus::Focus;
+/// use kaleidoscope_focus::testing::Replay;
+///
+/// let capture = "0.000010 > version \\n\n0.002000 < 0.1.0\\r\\n.\\r\\n\n";
+/// let replay = Replay::from_capture(capture.as_bytes())?;
+/// let mut conn = Focus::builder().interval(0).open_transport(replay.clone());
+///
+/// assert_eq!(conn.command("version")?, "0.1.0");
+/// assert!(replay.is_finished());
+/// assert!(conn.command("help").is_err());
+/// # Ok::<(), kaleidoscope_focus::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Replay {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+struct ReplayState {
+    exchanges: VecDeque<Exchange>,
+    replayed: usize,
+    matched: usize,
+    output: VecDeque<u8>,
+    divergence: Option<String>,
+}
+
+#[derive(Default)]
+struct Exchange {
+    request: Vec<u8>,
+    reply: Vec<u8>,
+}
+
+impl Replay {
+    /// Create a replay from a list of trace events.
+    ///
+    /// Consecutive events in the same direction are joined together: the data
+    /// sent makes up a request, and the data received after it, its reply.
+    /// Events without data are ignored.
+    pub fn new(events: impl IntoIterator<Item = TraceEvent>) -> Self {
+        let mut exchanges: VecDeque<Exchange> = VecDeque::new();
+        let mut output = VecDeque::new();
+
+        for event in events.into_iter().filter(|e| !e.data.is_empty()) {
+            match event.direction {
+                Direction::Sent => match exchanges.back_mut() {
+                    Some(last) if last.reply.is_empty() => last.request.extend(event.data),
+                    _ => exchanges.push_back(Exchange {
+                        request: event.data,
+                        reply: vec![],
+                    }),
+                },
+                // Anything received before the first request is there right away.
+                Direction::Received => match exchanges.back_mut() {
+                    Some(last) => last.reply.extend(event.data),
+                    None => output.extend(event.data),
+                },
+            }
+        }
+
+        Self {
+            state: Arc::new(Mutex::new(ReplayState {
+                exchanges,
+                replayed: 0,
+                matched: 0,
+                output,
+                divergence: None,
+            })),
+        }
+    }
+
+    /// Create a replay from the contents of a capture file.
+    ///
+    /// Empty lines are ignored. Returns an error if the capture cannot be read,
+    /// or contains invalid events.
+    pub fn from_capture(reader: impl BufRead) -> Result<Self> {
+        let mut events = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            if !line.is_empty() {
+                events.push(line.parse()?);
+            }
+        }
+        Ok(Self::new(events))
+    }
+
+    /// Return the number of requests not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.state().exchanges.len()
+    }
+
+    /// Return whether every recorded request has been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Return a description of how the requests diverged from the capture, if
+    /// they did.
+    pub fn divergence(&self) -> Option<String> {
+        self.state().divergence.clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ReplayState {
+    fn feed(&mut self, data: &[u8]) -> std::result::Result<(), String> {
+        for (i, &byte) in data.iter().enumerate() {
+            let exchange = match self.exchanges.front() {
+                Some(exchange) => exchange,
+                None => {
+                    return Err(format!(
+                        "Unexpected request after the end of the capture: `{}`",
+                        escape(&data[i..])
+                    ))
+                }
+            };
+            if exchange.request[self.matched] != byte {
+                return Err(format!(
+                    "Request #{} diverged from the capture at byte {}: expected `{}`, got `{}`",
+                    self.replayed,
+                    self.matched,
+                    escape(&exchange.request[self.matched..]),
+                    escape(&data[i..])
+                ));
+            }
+
+            self.matched += 1;
+            if self.matched == exchange.request.len() {
+                let exchange = self.exchanges.pop_front().unwrap_or_default();
+                self.output.extend(exchange.reply);
+                self.replayed += 1;
+                self.matched = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).escape_debug().to_string()
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.output.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+
+        let n = buf.len().min(state.output.len());
+        for (dst, src) in buf.iter_mut().zip(state.output.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.divergence.is_none() {
+            if let Err(divergence) = state.feed(buf) {
+                state.divergence = Some(divergence);
+            }
+        }
+        match &state.divergence {
+            Some(divergence) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                divergence.as_str(),
+            )),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FocusTransport for Replay {
+    fn bytes_available(&mut self) -> io::Result<usize> {
+        Ok(self.state().output.len())
+    }
+
+    fn name(&self) -> Option<String> {
+        Some("replay".to_string())
+    }
+}
+
+#[cfg(feature = "async")]
+mod nonblocking {
+    use super::MockKeyboard;
+    use std::io::{self, Read, Write};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    impl AsyncRead for MockKeyboard {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            {
+                let mut state = this.state();
+                if state.output.is_empty() {
+                    state.reader = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+
+            let n = this.read(buf.initialize_unfilled())?;
+            buf.advance(n);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for MockKeyboard {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.get_mut().write(buf))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Focus;
+    use std::time::Duration;
+
+    fn event(direction: Direction, data: &str) -> TraceEvent {
+        TraceEvent {
+            elapsed: Duration::ZERO,
+            direction,
+            data: data.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn empty_events_are_ignored() {
+        let replay = Replay::new([
+            event(Direction::Sent, ""),
+            event(Direction::Sent, "version \n"),
+            event(Direction::Received, "1.0\r\n.\r\n"),
+            event(Direction::Sent, ""),
+            event(Direction::Received, ""),
+            event(Direction::Sent, "help "),
+            event(Direction::Sent, ""),
+            event(Direction::Sent, "\n"),
+            event(Direction::Received, "version\r\n.\r\n"),
+        ]);
+        assert_eq!(replay.remaining(), 2);
+
+        let mut conn = Focus::builder().interval(0).open_transport(replay.clone());
+        assert_eq!(conn.command("version").unwrap(), "1.0");
+        assert_eq!(conn.command("help").unwrap(), "version");
+        assert!(replay.is_finished());
+
+        assert!(Replay::new([event(Direction::Sent, "")]).is_finished());
+    }
+
+    #[test]
+    fn divergences_are_reported() {
+        let replay = Replay::new([
+            event(Direction::Sent, "version \n"),
+            event(Direction::Received, "1.0\r\n.\r\n"),
+        ]);
+        let mut conn = Focus::builder().interval(0).open_transport(replay.clone());
+
+        assert!(conn.command("help").is_err());
+        assert!(replay.divergence().unwrap().contains("at byte 0"));
+        // Every request after a divergence fails.
+        assert!(conn.command("version").is_err());
+        assert_eq!(replay.remaining(), 1);
+    }
+
+    #[test]
+    fn requests_beyond_the_capture_are_divergences() {
+        let replay = Replay::new([]);
+        let mut conn = Focus::builder().interval(0).open_transport(replay.clone());
+
+        assert!(conn.command("version").is_err());
+        assert!(replay.divergence().unwrap().contains("end of the capture"));
+    }
+}
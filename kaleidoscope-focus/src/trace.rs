@@ -0,0 +1,288 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tracing the data exchanged with the keyboard.
+//!
+//! A trace hook - set with [`Focus::set_trace`] - is called with a
+//! [`TraceEvent`] for every chunk of data sent to, or received from the
+//! keyboard.
+//!
+//! Events can be saved into a capture file with [`capture`]. A capture file
+//! has one event per line: the time elapsed since the connection was opened,
+//! in seconds, the direction (`>` for sent, `<` for received), and the data,
+//! with anything but printable ASCII escaped, like `\r`, `\n` or `\x1b`:
+//!
+//! ```text
+//! 0.000012 > version \n
+//! 0.003411 < 0.1.0\r\n.\r\n
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! # use kaleidoscope_focus::{Focus, testing::MockKeyboard};
+//! use kaleidoscope_focus::trace::{Direction, TraceEvent};
+//! use std::sync::{Arc, Mutex};
+//!
+//! # fn main() -> Result<(), kaleidoscope_focus::Error> {
+//! # let keyboard = MockKeyboard::new().with_command("version", "0.1.0");
+//! # let mut conn = Focus::builder().interval(0).chunk_size(0).open_transport(keyboard);
+//! let events = Arc::new(Mutex::new(vec![]));
+//! let recorder = events.clone();
+//! conn.set_trace(move |event| recorder.lock().unwrap().push(event.clone()));
+//! conn.command("version")?;
+//!
+//! let events = events.lock().unwrap();
+//! assert_eq!(events[0].direction, Direction::Sent);
+//! assert_eq!(events[0].data, b"version \n");
+//! assert_eq!(events[1].data, b"0.1.0\r\n.\r\n");
+//!
+//! let line = events[1].to_string();
+//! assert!(line.ends_with(r" < 0.1.0\r\n.\r\n"));
+//! assert_eq!(line.parse::<TraceEvent>()?.data, events[1].data);
+//! #   Ok(())
+//! # }
+//! ```
+
+use crate::{Error, Result};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The direction data travelled in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Sent to the keyboard.
+    Sent,
+    /// Received from the keyboard.
+    Received,
+}
+
+/// A chunk of data sent to, or received from the keyboard.
+///
+/// Displayed - and parsed - as a line of a capture file. See the
+/// [module documentation](crate::trace) for the format.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TraceEvent {
+    /// The time elapsed since the connection was opened.
+    pub elapsed: Duration,
+    /// The direction the data travelled in.
+    pub direction: Direction,
+    /// The data itself.
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        write!(
+            f,
+            "{}.{:06} {} ",
+            self.elapsed.as_secs(),
+            self.elapsed.subsec_micros(),
+            direction
+        )?;
+        for &byte in &self.data {
+            match byte {
+                b'\\' => write!(f, "\\\\")?,
+                b'\r' => write!(f, "\\r")?,
+                b'\n' => write!(f, "\\n")?,
+                b'\t' => write!(f, "\\t")?,
+                b' '..=b'~' => write!(f, "{}", byte as char)?,
+                _ => write!(f, "\\x{:02x}", byte)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TraceEvent {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidValue(format!("{}: `{}`", reason, s));
+        let mut parts = s.splitn(3, ' ');
+
+        let elapsed = parts
+            .next()
+            .and_then(|e| e.split_once('.'))
+            .filter(|(_, micros)| micros.len() == 6)
+            .and_then(|(secs, micros)| Some((secs.parse().ok()?, micros.parse().ok()?)))
+            .map(|(secs, micros)| Duration::from_secs(secs) + Duration::from_micros(micros))
+            .ok_or_else(|| invalid("Invalid timestamp in trace event"))?;
+        let direction = match parts.next() {
+            Some(">") => Direction::Sent,
+            Some("<") => Direction::Received,
+            _ => return Err(invalid("Invalid direction in trace event")),
+        };
+
+        let escaped = parts.next().unwrap_or_default().as_bytes();
+        let mut data = Vec::with_capacity(escaped.len());
+        let mut i = 0;
+        while i < escaped.len() {
+            if escaped[i] != b'\\' {
+                data.push(escaped[i]);
+                i += 1;
+                continue;
+            }
+            let (byte, len) = match escaped.get(i + 1) {
+                Some(b'\\') => (b'\\', 2),
+                Some(b'r') => (b'\r', 2),
+                Some(b'n') => (b'\n', 2),
+                Some(b't') => (b'\t', 2),
+                Some(b'x') => escaped
+                    .get(i + 2..i + 4)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .map(|byte| (byte, 4))
+                    .ok_or_else(|| invalid("Invalid escape in trace event"))?,
+                _ => return Err(invalid("Invalid escape in trace event")),
+            };
+            data.push(byte);
+            i += len;
+        }
+
+        Ok(Self {
+            elapsed,
+            direction,
+            data,
+        })
+    }
+}
+
+/// Create a trace hook that writes events into a capture file.
+///
+/// Every event is written as soon as it happens. Since trace hooks cannot
+/// fail, errors writing the capture are ignored.
+///
+/// ```no_run
+/// # use kaleidoscope_focus::{trace, Focus};
+/// # use std::fs::File;
+/// # fn main() -> Result<(), kaleidoscope_focus::Error> {
+/// let mut conn = Focus::create("/dev/ttyACM0").open()?;
+/// conn.set_trace(trace::capture(File::create("focus.trace")?));
+/// #   Ok(())
+/// # }
+/// ```
+pub fn capture(writer: impl Write + 'static) -> impl Fn(&TraceEvent) {
+    let writer = Mutex::new(writer);
+    move |event| {
+        if let Ok(mut writer) = writer.lock() {
+            let _ = writeln!(writer, "{}", event).and_then(|_| writer.flush());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(direction: Direction, data: &[u8]) -> TraceEvent {
+        TraceEvent {
+            elapsed: Duration::from_micros(1_234_567),
+            direction,
+            data: data.to_vec(),
+        }
+    }
+
+    fn round_trip(event: &TraceEvent) -> TraceEvent {
+        event.to_string().parse().unwrap()
+    }
+
+    #[test]
+    fn events_are_escaped() {
+        assert_eq!(
+            event(Direction::Sent, b"a\\b\r\n\t\x1b\xff ").to_string(),
+            r"1.234567 > a\\b\r\n\t\x1b\xff "
+        );
+        assert_eq!(event(Direction::Received, b"").to_string(), "1.234567 < ");
+    }
+
+    #[test]
+    fn events_survive_a_round_trip() {
+        let all: Vec<u8> = (0..=255).collect();
+        for data in [
+            &b"version \n"[..],
+            b"0.1.0\r\n.\r\n",
+            b"\\n is not a newline\\",
+            b"\xc3\x28 is not UTF-8",
+            "caf\u{e9}".as_bytes(),
+            b"",
+            &all,
+        ] {
+            for direction in [Direction::Sent, Direction::Received] {
+                let event = event(direction, data);
+                let line = event.to_string();
+                assert!(!line.contains('\n'), "{}", line);
+                assert_eq!(round_trip(&event), event, "{}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn timestamps_survive_a_round_trip() {
+        let mut event = event(Direction::Sent, b"help \n");
+        event.elapsed = Duration::from_micros(3_600_000_042);
+        assert!(event.to_string().starts_with("3600.000042 > "));
+        assert_eq!(round_trip(&event), event);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        for line in [
+            "",
+            "1.234567",
+            "1.234567 ! help",
+            "1.23 > help",
+            "1.2345678 > help",
+            "x.234567 > help",
+            "1,234567 > help",
+            "-1.234567 > help",
+        ] {
+            assert!(line.parse::<TraceEvent>().is_err(), "{}", line);
+        }
+        // Empty data may have lost its separator to an editor stripping
+        // trailing whitespace.
+        for line in ["1.234567 > ", "1.234567 >"] {
+            assert_eq!(line.parse::<TraceEvent>().unwrap().data, b"");
+        }
+    }
+
+    #[test]
+    fn invalid_escapes_are_rejected() {
+        for line in [
+            r"1.234567 > \",
+            r"1.234567 > a\",
+            r"1.234567 > \a",
+            r"1.234567 > \x",
+            r"1.234567 > \x1",
+            r"1.234567 > \xg0",
+            r"1.234567 > \x+f",
+            "1.234567 > \\x\u{e9}",
+        ] {
+            assert!(
+                matches!(line.parse::<TraceEvent>(), Err(Error::InvalidValue(_))),
+                "{}",
+                line
+            );
+        }
+    }
+}
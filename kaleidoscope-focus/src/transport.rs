@@ -0,0 +1,105 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serialport::SerialPort;
+use std::io;
+
+/// The channel [`Focus`](crate::Focus) uses to talk to a keyboard.
+///
+/// By default, the library talks to keyboards over a serial port, but anything
+/// that can read and write bytes can serve as a transport: a pseudo-terminal, a
+/// socket, or an in-memory fake used for testing.
+///
+/// Reads are expected to behave like those of a serial port with a timeout:
+/// when there is no data available, they should return an error of kind
+/// [`io::ErrorKind::TimedOut`] or [`io::ErrorKind::WouldBlock`] rather than
+/// block forever, and [`bytes_available`](FocusTransport::bytes_available)
+/// should return 0. Reads returning `Ok(0)` mean end of file: that the
+/// keyboard went away.
+///
+/// # Examples
+///
+/// ```
+/// # use kaleidoscope_focus::{Focus, FocusTransport};
+/// # use std::io::{self, Read, Write};
+/// /// A transport that answers every request with the same reply.
+/// struct Parrot {
+///     pending: Vec<u8>,
+/// }
+///
+/// impl Read for Parrot {
+///     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+///         let n = buf.len().min(self.pending.len());
+///         buf[..n].copy_from_slice(&self.pending[..n]);
+///         self.pending.drain(..n);
+///         Ok(n)
+///     }
+/// }
+///
+/// impl Write for Parrot {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+///         if buf.ends_with(b"\n") {
+///             self.pending.extend(b"squawk\r\n.\r\n");
+///         }
+///         Ok(buf.len())
+///     }
+///     fn flush(&mut self) -> io::Result<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// impl FocusTransport for Parrot {
+///     fn bytes_available(&mut self) -> io::Result<usize> {
+///         Ok(self.pending.len())
+///     }
+/// }
+///
+/// let mut conn = Focus::builder()
+///     .interval(0)
+///     .open_transport(Parrot { pending: vec![] });
+/// assert_eq!(conn.command("version")?, "squawk");
+/// # Ok::<(), kaleidoscope_focus::Error>(())
+/// ```
+pub trait FocusTransport: io::Read + io::Write + Send {
+    /// Return the number of bytes that can be read without blocking.
+    fn bytes_available(&mut self) -> io::Result<usize>;
+
+    /// Set the Data Terminal Ready control signal.
+    ///
+    /// Transports without the concept of control signals can rely on the
+    /// default implementation, which does nothing.
+    fn write_data_terminal_ready(&mut self, _level: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Return the name of the underlying device, if there is one.
+    fn name(&self) -> Option<String> {
+        None
+    }
+}
+
+impl FocusTransport for Box<dyn SerialPort> {
+    fn bytes_available(&mut self) -> io::Result<usize> {
+        Ok(self.bytes_to_read()? as usize)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> io::Result<()> {
+        Ok(SerialPort::write_data_terminal_ready(self.as_mut(), level)?)
+    }
+
+    fn name(&self) -> Option<String> {
+        SerialPort::name(self.as_ref())
+    }
+}